@@ -0,0 +1,194 @@
+use crate::event_queue::*;
+use crate::midi_message::*;
+
+// midi clock sends 24 pulses per quarter note
+pub const PULSES_PER_BEAT: u32 = 24;
+// song position pointer counts sixteenth notes
+const PULSES_PER_SONG_POSITION: u32 = 6;
+// how far a new pulse interval moves the tempo, evens out jitter from the backend
+const TEMPO_SMOOTHING: f64 = 0.1;
+// pulses further apart than this aren't a tempo, the sender paused or dropped out
+const MAX_PULSE_INTERVAL: u64 = 250_000;
+// no clock for this long and the tempo is forgotten
+const CLOCK_TIMEOUT: f32 = 0.5;
+
+// follows the clock and transport of a sequencer or DAW sending midi clock,
+// so the game can move in time with the music
+pub struct BeatClock {
+    is_playing: bool,
+    // start and continue take effect on the next pulse, it doesn't move the position
+    is_waiting_for_pulse: bool,
+    // pulses since the start of the song
+    pulses: u32,
+    // smoothed microseconds between pulses
+    pulse_interval: Option<f64>,
+    last_pulse_timestamp: Option<u64>,
+    // seconds since the last pulse, moves the beat along in between pulses
+    since_pulse: f32,
+    beats_this_frame: u32,
+}
+
+impl BeatClock {
+    pub fn new() -> Self {
+        Self {
+            is_playing: false,
+            is_waiting_for_pulse: false,
+            pulses: 0,
+            pulse_interval: None,
+            last_pulse_timestamp: None,
+            since_pulse: 0f32,
+            beats_this_frame: 0,
+        }
+    }
+
+    // call every frame with the events the midi source read
+    pub fn update(&mut self, dt: f32, events: &[InputEvent]) {
+        self.beats_this_frame = 0;
+        self.since_pulse += dt;
+        if self.since_pulse > CLOCK_TIMEOUT {
+            self.pulse_interval = None;
+            self.last_pulse_timestamp = None;
+        }
+        for event in events {
+            match event.message {
+                MidiMessage::TimingClock => self.pulse(event.timestamp),
+                MidiMessage::Start => {
+                    self.pulses = 0;
+                    self.is_playing = true;
+                    self.is_waiting_for_pulse = true;
+                }
+                MidiMessage::Continue => {
+                    self.is_playing = true;
+                    self.is_waiting_for_pulse = true;
+                }
+                MidiMessage::Stop => self.is_playing = false,
+                MidiMessage::SongPosition { beats } => {
+                    self.pulses = beats as u32 * PULSES_PER_SONG_POSITION;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn pulse(&mut self, timestamp: u64) {
+        if let Some(last) = self.last_pulse_timestamp {
+            let interval = timestamp.saturating_sub(last);
+            if interval > 0 && interval < MAX_PULSE_INTERVAL {
+                let interval = interval as f64;
+                self.pulse_interval = Some(match self.pulse_interval {
+                    Some(smoothed) => smoothed + (interval - smoothed) * TEMPO_SMOOTHING,
+                    None => interval,
+                });
+            }
+        }
+        self.last_pulse_timestamp = Some(timestamp);
+        self.since_pulse = 0f32;
+        // sequencers keep sending clock while stopped, only the tempo follows it then
+        if !self.is_playing {
+            return;
+        }
+        if self.is_waiting_for_pulse {
+            self.is_waiting_for_pulse = false;
+        } else {
+            self.pulses += 1;
+        }
+        if self.pulses.is_multiple_of(PULSES_PER_BEAT) {
+            self.beats_this_frame += 1;
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.is_playing
+    }
+
+    // None until a few pulses have arrived
+    pub fn bpm(&self) -> Option<f32> {
+        self.pulse_interval
+            .map(|interval| (60_000_000f64 / (interval * PULSES_PER_BEAT as f64)) as f32)
+    }
+
+    // quarter notes since the start of the song
+    pub fn beat(&self) -> f32 {
+        let mut pulses = self.pulses as f32;
+        // in between pulses the beat keeps moving at the current tempo
+        if self.is_playing && !self.is_waiting_for_pulse {
+            if let Some(interval) = self.pulse_interval {
+                let seconds = interval as f32 / 1_000_000f32;
+                pulses += (self.since_pulse / seconds).min(1f32);
+            }
+        }
+        pulses / PULSES_PER_BEAT as f32
+    }
+
+    // 0 right on the beat going up to 1 just before the next one, None while stopped
+    pub fn beat_phase(&self) -> Option<f32> {
+        if self.is_playing {
+            Some(self.beat().fract())
+        } else {
+            None
+        }
+    }
+
+    // how many beats started this frame, for things that happen on the beat
+    pub fn beats_this_frame(&self) -> u32 {
+        self.beats_this_frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 120 bpm
+    const PULSE_INTERVAL: u64 = 500_000 / PULSES_PER_BEAT as u64;
+
+    fn frame(clock: &mut BeatClock, parser: &mut MidiParser, bytes: &[u8], timestamp: u64) {
+        let events: Vec<InputEvent> = parser
+            .parse(bytes)
+            .into_iter()
+            .map(|message| InputEvent {
+                timestamp,
+                port: 0,
+                message,
+                received: None,
+            })
+            .collect();
+        clock.update(0f32, &events);
+    }
+
+    #[test]
+    fn start_counts_beats_and_tempo() {
+        let mut clock = BeatClock::new();
+        let mut parser = MidiParser::new();
+        // the clock runs before the song starts
+        for pulse in 0..4 {
+            frame(&mut clock, &mut parser, &[0xF8], pulse * PULSE_INTERVAL);
+        }
+        assert!(!clock.is_playing());
+        assert_eq!(clock.beat_phase(), None);
+        frame(&mut clock, &mut parser, &[0xFA], 4 * PULSE_INTERVAL);
+
+        let mut beats = 0;
+        for pulse in 4..(4 + PULSES_PER_BEAT as u64 * 2) {
+            frame(&mut clock, &mut parser, &[0xF8], pulse * PULSE_INTERVAL);
+            beats += clock.beats_this_frame();
+        }
+        // the first pulse after start is the downbeat, then one more beat
+        assert_eq!(beats, 2);
+        assert_eq!(clock.beat(), (PULSES_PER_BEAT * 2 - 1) as f32 / 24f32);
+        assert!((clock.bpm().unwrap() - 120f32).abs() < 1f32);
+    }
+
+    #[test]
+    fn song_position_moves_the_beat_while_stopped() {
+        let mut clock = BeatClock::new();
+        let mut parser = MidiParser::new();
+        frame(&mut clock, &mut parser, &[0xFA, 0xF8, 0xFC], 0);
+        // bar 3 in 4/4, 32 sixteenths in
+        frame(&mut clock, &mut parser, &[0xF2, 32, 0], 0);
+        assert_eq!(clock.beat(), 8f32);
+        frame(&mut clock, &mut parser, &[0xFB, 0xF8], 0);
+        assert_eq!(clock.beats_this_frame(), 1);
+        assert_eq!(clock.beat_phase(), Some(0f32));
+    }
+}
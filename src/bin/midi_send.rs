@@ -0,0 +1,182 @@
+// sends midi to the game over the network, to try rtp-midi and osc input without a phone
+//   cargo run --bin midi_send -- osc
+//   cargo run --bin midi_send -- rtp 127.0.0.1:5004 note 64 100
+use std::env;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str =
+    "usage: midi_send osc|rtp [host:port] [note NOTE VELOCITY | cc CONTROLLER VALUE]";
+// rtp-midi session
+const SESSION_NAME: &str = "midi_send";
+const SSRC: u32 = 0x6d69_6469;
+const INVITATION_TOKEN: u32 = 1;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+enum Message {
+    Note(u8, u8),
+    ControlChange(u8, u8),
+}
+
+impl Message {
+    fn to_bytes(self) -> [u8; 3] {
+        match self {
+            Message::Note(note, velocity) => [0x90, note, velocity],
+            Message::ControlChange(controller, value) => [0xB0, controller, value],
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    let protocol = args.next().unwrap_or_default();
+    let default_address = match protocol.as_str() {
+        "osc" => "127.0.0.1:9000",
+        "rtp" => "127.0.0.1:5004",
+        _ => {
+            println!("{}", USAGE);
+            return;
+        }
+    };
+    let address = match args.peek() {
+        Some(arg) if arg.contains(':') => args.next().unwrap(),
+        _ => default_address.to_string(),
+    };
+    let address = match address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut all| all.next())
+    {
+        Some(address) => address,
+        None => {
+            println!("can't find {}", address);
+            return;
+        }
+    };
+    let args: Vec<String> = args.collect();
+    let messages = match parse_messages(&args) {
+        Some(messages) => messages,
+        None => {
+            println!("{}", USAGE);
+            return;
+        }
+    };
+    let result = match protocol.as_str() {
+        "osc" => send_osc(address, &messages),
+        _ => send_rtp_midi(address, &messages),
+    };
+    if let Err(err) = result {
+        println!("can't send to {}: {}", address, err);
+    }
+}
+
+// each message with how long to wait after it, without any the demo taps
+// note 64 a few times and sweeps cc 1 up and down
+fn parse_messages(args: &[String]) -> Option<Vec<(Message, Duration)>> {
+    if args.is_empty() {
+        let mut messages = Vec::new();
+        for _ in 0..3 {
+            messages.push((Message::Note(64, 100), Duration::from_millis(150)));
+            messages.push((Message::Note(64, 0), Duration::from_millis(350)));
+        }
+        for value in (0..=127).step_by(4).chain((0..=127).rev().step_by(4)) {
+            messages.push((Message::ControlChange(1, value), Duration::from_millis(20)));
+        }
+        return Some(messages);
+    }
+    let data = |index: usize| {
+        args.get(index)
+            .and_then(|arg| arg.parse::<u8>().ok())
+            .filter(|value| *value < 128)
+    };
+    let message = match args[0].as_str() {
+        "note" if args.len() == 3 => Message::Note(data(1)?, data(2)?),
+        "cc" if args.len() == 3 => Message::ControlChange(data(1)?, data(2)?),
+        _ => return None,
+    };
+    Some(vec![(message, Duration::from_millis(0))])
+}
+
+// as the game reads them, /note and /cc with int arguments
+fn send_osc(address: SocketAddr, messages: &[(Message, Duration)]) -> io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    for (message, delay) in messages {
+        let (osc_address, arguments) = match *message {
+            Message::Note(note, velocity) => ("/note", [note, velocity]),
+            Message::ControlChange(controller, value) => ("/cc", [controller, value]),
+        };
+        let mut packet = Vec::new();
+        // strings end with a zero and are padded to 4 bytes
+        for string in [osc_address, ",ii"].iter() {
+            packet.extend_from_slice(string.as_bytes());
+            packet.extend(vec![0; 4 - string.len() % 4]);
+        }
+        for argument in arguments.iter() {
+            packet.extend_from_slice(&(*argument as i32).to_be_bytes());
+        }
+        socket.send_to(&packet, address)?;
+        thread::sleep(*delay);
+    }
+    Ok(())
+}
+
+// invites the game on its control and data port, like network midi on macOS does,
+// then sends every message in an rtp packet of its own
+fn send_rtp_midi(address: SocketAddr, messages: &[(Message, Duration)]) -> io::Result<()> {
+    let control = UdpSocket::bind("0.0.0.0:0")?;
+    let data = UdpSocket::bind("0.0.0.0:0")?;
+    let data_port = address.port().checked_add(1).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the rtp midi control port can't be the last port",
+        )
+    })?;
+    let data_address = SocketAddr::new(address.ip(), data_port);
+    invite(&control, address)?;
+    invite(&data, data_address)?;
+
+    let start = Instant::now();
+    for (sequence, (message, delay)) in messages.iter().enumerate() {
+        let bytes = message.to_bytes();
+        let mut packet = vec![0x80, 0x61];
+        packet.extend_from_slice(&(sequence as u16).to_be_bytes());
+        // 10 kHz clock
+        let timestamp = (start.elapsed().as_micros() / 100) as u32;
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&SSRC.to_be_bytes());
+        // short header without journal, the length of the one command
+        packet.push(bytes.len() as u8);
+        packet.extend_from_slice(&bytes);
+        data.send_to(&packet, data_address)?;
+        thread::sleep(*delay);
+    }
+    // ends the session so the game doesn't wait for more
+    control.send_to(&session_command(b"BY"), address)?;
+    Ok(())
+}
+
+fn session_command(command: &[u8; 2]) -> Vec<u8> {
+    let mut packet = vec![0xFF, 0xFF, command[0], command[1]];
+    packet.extend_from_slice(&2u32.to_be_bytes());
+    packet.extend_from_slice(&INVITATION_TOKEN.to_be_bytes());
+    packet.extend_from_slice(&SSRC.to_be_bytes());
+    packet
+}
+
+fn invite(socket: &UdpSocket, address: SocketAddr) -> io::Result<()> {
+    let mut invitation = session_command(b"IN");
+    invitation.extend_from_slice(SESSION_NAME.as_bytes());
+    invitation.push(0);
+    socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    socket.send_to(&invitation, address)?;
+    let mut reply = [0u8; 256];
+    let (len, _) = socket.recv_from(&mut reply)?;
+    if reply[..len].starts_with(b"\xFF\xFFOK") {
+        Ok(())
+    } else {
+        Err(io::Error::other("the invitation was turned down"))
+    }
+}
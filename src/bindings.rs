@@ -0,0 +1,517 @@
+use crate::input::*;
+use crate::sysex::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+
+pub const BINDINGS_PATH: &str = "bindings.ron";
+
+#[derive(Hash, Eq, Ord, PartialOrd, Clone, Debug, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GameAction {
+    MoveLeft,
+    MoveRight,
+    Jump,
+    Shoot,
+    Latch,
+    Crouch,
+    JumpStrength,
+    WaterSpeed,
+    WaterStrength,
+    CameraX,
+    CameraY,
+}
+
+impl GameAction {
+    pub const ALL: [GameAction; 11] = [
+        GameAction::MoveLeft,
+        GameAction::MoveRight,
+        GameAction::Jump,
+        GameAction::Shoot,
+        GameAction::Latch,
+        GameAction::Crouch,
+        GameAction::JumpStrength,
+        GameAction::WaterSpeed,
+        GameAction::WaterStrength,
+        GameAction::CameraX,
+        GameAction::CameraY,
+    ];
+
+    // buttons are pressed, everything else is read as a fraction
+    pub fn is_button(self) -> bool {
+        use GameAction::*;
+        match self {
+            MoveLeft | MoveRight | Jump | Shoot => true,
+            Latch | Crouch | JumpStrength | WaterSpeed | WaterStrength | CameraX | CameraY => false,
+        }
+    }
+}
+
+// how an endless encoder encodes the amount it was turned
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum EncoderMode {
+    // a normal knob or fader, the value is the position
+    #[default]
+    Absolute,
+    // 1 to 63 up, 127 down to 64 down
+    TwosComplement,
+    // 64 is no change, above goes up, below goes down
+    Offset64,
+    // bit 6 is the sign, the rest is the amount
+    SignMagnitude,
+}
+
+impl EncoderMode {
+    // steps turned since the last message, 0 for absolute controls
+    pub fn delta(self, value: u8) -> i32 {
+        let value = value as i32;
+        match self {
+            EncoderMode::Absolute => 0,
+            EncoderMode::TwosComplement if value >= 64 => value - 128,
+            EncoderMode::TwosComplement => value,
+            EncoderMode::Offset64 => value - 64,
+            EncoderMode::SignMagnitude if value & 0x40 != 0 => -(value & 0x3F),
+            EncoderMode::SignMagnitude => value & 0x3F,
+        }
+    }
+}
+
+fn default_sensitivity() -> f32 {
+    1f32 / 127f32
+}
+
+// how the position of a fader is turned into the value the game reads
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ResponseCurve {
+    #[default]
+    Linear,
+    // slow at the bottom and fast at the top, higher is steeper
+    Exponential(f32),
+    // fast at the bottom and slow at the top, the mirror of exponential
+    Logarithmic(f32),
+    // slow at both ends, fine control around the middle
+    SCurve,
+}
+
+impl ResponseCurve {
+    // 0 stays 0 and 1 stays 1 for every curve
+    pub fn apply(self, fraction: f32) -> f32 {
+        match self {
+            ResponseCurve::Linear => fraction,
+            ResponseCurve::Exponential(steepness) if steepness > 0f32 => {
+                ((steepness * fraction).exp() - 1f32) / (steepness.exp() - 1f32)
+            }
+            ResponseCurve::Logarithmic(steepness) if steepness > 0f32 => {
+                (1f32 + fraction * (steepness.exp() - 1f32)).ln() / steepness
+            }
+            ResponseCurve::Exponential(_) | ResponseCurve::Logarithmic(_) => fraction,
+            ResponseCurve::SCurve => fraction * fraction * (3f32 - 2f32 * fraction),
+        }
+    }
+}
+
+// the range a fader really reaches, cheap ones rarely get to exactly 0 or 127
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Calibration {
+    pub fn new(fraction: f32) -> Self {
+        Self {
+            min: fraction,
+            max: fraction,
+        }
+    }
+
+    pub fn record(&mut self, fraction: f32) {
+        self.min = self.min.min(fraction);
+        self.max = self.max.max(fraction);
+    }
+
+    // stretches min to max back out to 0 to 1
+    pub fn apply(&self, fraction: f32) -> f32 {
+        let range = self.max - self.min;
+        if range <= 0f32 {
+            return fraction;
+        }
+        ((fraction - self.min) / range).clamp(0f32, 1f32)
+    }
+}
+
+// thresholds for faders used as switches, like the latch and crouch faders
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Trigger {
+    // reached going down past this
+    pub low: f32,
+    // reached going up past this
+    pub high: f32,
+    // how far the fader has to move back from a threshold before it can be reached again,
+    // keeps a jittery fader resting on a threshold from firing every frame
+    pub hysteresis: f32,
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Self {
+            low: 0.3f32,
+            high: 0.7f32,
+            hysteresis: 0.02f32,
+        }
+    }
+}
+
+impl Trigger {
+    pub fn threshold(&self, limit: SliderLimitCheck) -> f32 {
+        match limit {
+            SliderLimitCheck::Higher => self.high,
+            SliderLimitCheck::Lower => self.low,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Binding {
+    pub control: ControlId,
+    #[serde(default)]
+    pub encoder: EncoderMode,
+    // fraction moved per encoder step
+    #[serde(default = "default_sensitivity")]
+    pub sensitivity: f32,
+    // going past either end wraps around instead of stopping
+    #[serde(default)]
+    pub wrap: bool,
+    // recorded on the bindings screen, None uses the full 0 to 127
+    #[serde(default)]
+    pub calibration: Option<Calibration>,
+    // fraction at each end of the range that reads as fully 0 or 1
+    #[serde(default)]
+    pub dead_zone: f32,
+    #[serde(default)]
+    pub curve: ResponseCurve,
+    #[serde(default)]
+    pub trigger: Trigger,
+    // soft takeover, the game keeps its value until the fader is moved across it
+    #[serde(default)]
+    pub pickup: bool,
+}
+
+impl Binding {
+    pub fn new(control: ControlId) -> Self {
+        Self {
+            control,
+            encoder: EncoderMode::Absolute,
+            sensitivity: default_sensitivity(),
+            wrap: false,
+            calibration: None,
+            dead_zone: 0f32,
+            curve: ResponseCurve::Linear,
+            trigger: Trigger::default(),
+            pickup: false,
+        }
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.encoder != EncoderMode::Absolute
+    }
+
+    // only absolute faders can be out of step with the game
+    pub fn uses_pickup(&self) -> bool {
+        self.pickup && !self.is_relative()
+    }
+
+    // calibration, then dead zones, then the response curve
+    pub fn shape(&self, fraction: f32) -> f32 {
+        let fraction = match &self.calibration {
+            Some(calibration) => calibration.apply(fraction),
+            None => fraction,
+        };
+        let dead_zone = self.dead_zone.clamp(0f32, 0.49f32);
+        let fraction = ((fraction - dead_zone) / (1f32 - 2f32 * dead_zone)).clamp(0f32, 1f32);
+        self.curve.apply(fraction)
+    }
+}
+
+// game state that can be shown on the controller
+#[derive(Clone, Debug, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FeedbackSource {
+    LatchOpen,
+    GunLoaded,
+    Crouching,
+    // the value the game has for a fader action, moves motorized faders
+    Action(GameAction),
+}
+
+// lights a pad with a note or sets a fader or its leds with a control change
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Feedback {
+    pub source: FeedbackSource,
+    pub control: ControlId,
+    // sent when the source is on, pads usually pick their color from it
+    #[serde(default = "default_feedback_on")]
+    pub on: u8,
+    #[serde(default)]
+    pub off: u8,
+}
+
+fn default_feedback_on() -> u8 {
+    127
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bindings {
+    pub actions: BTreeMap<GameAction, Binding>,
+    // sent back to the device, see MidiFeedback
+    #[serde(default)]
+    pub feedback: Vec<Feedback>,
+}
+
+impl Bindings {
+    // the controller used in the video
+    pub fn video_controller() -> Self {
+        let mut bindings = Self::default();
+        bindings.bind(GameAction::MoveLeft, ControlId::note(0, 41));
+        bindings.bind(GameAction::MoveRight, ControlId::note(0, 45));
+        bindings.bind(GameAction::Jump, ControlId::note(0, 64));
+        bindings.bind(GameAction::Shoot, ControlId::note(0, 65));
+        bindings.bind(GameAction::JumpStrength, ControlId::cc(0, 0));
+        bindings.bind(GameAction::Latch, ControlId::cc(0, 1));
+        bindings.bind(GameAction::Crouch, ControlId::cc(0, 2));
+        bindings.bind(GameAction::WaterStrength, ControlId::cc(0, 3));
+        bindings.bind(GameAction::WaterSpeed, ControlId::cc(0, 4));
+        bindings.bind(GameAction::CameraX, ControlId::cc(0, 6));
+        bindings.bind(GameAction::CameraY, ControlId::cc(0, 7));
+        bindings
+    }
+
+    // keeps the encoder settings when only the control changes
+    pub fn bind(&mut self, action: GameAction, control: ControlId) {
+        match self.actions.get_mut(&action) {
+            Some(binding) => binding.control = control,
+            None => {
+                self.actions.insert(action, Binding::new(control));
+            }
+        }
+    }
+
+    pub fn unbind(&mut self, action: GameAction) {
+        self.actions.remove(&action);
+    }
+
+    pub fn control(&self, action: GameAction) -> Option<ControlId> {
+        self.actions.get(&action).map(|binding| binding.control)
+    }
+}
+
+// matches the reply to a device inquiry, leave family or model out to match every one of them
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IdentityMatch {
+    pub manufacturer: Vec<u8>,
+    #[serde(default)]
+    pub family: Option<u16>,
+    #[serde(default)]
+    pub model: Option<u16>,
+}
+
+impl IdentityMatch {
+    pub fn exact(identity: &DeviceIdentity) -> Self {
+        Self {
+            manufacturer: identity.manufacturer.clone(),
+            family: Some(identity.family),
+            model: Some(identity.model),
+        }
+    }
+
+    pub fn matches(&self, identity: &DeviceIdentity) -> bool {
+        self.manufacturer == identity.manufacturer
+            && self.family.is_none_or(|family| family == identity.family)
+            && self.model.is_none_or(|model| model == identity.model)
+    }
+}
+
+// bindings used when the device answers the inquiry with a matching identity,
+// or else when the connected port name contains device, ignoring case
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    pub device: String,
+    #[serde(default)]
+    pub identity: Option<IdentityMatch>,
+    pub bindings: Bindings,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BindingsFile {
+    pub default: Bindings,
+    #[serde(default)]
+    pub profiles: Vec<DeviceProfile>,
+}
+
+impl Default for BindingsFile {
+    fn default() -> Self {
+        Self {
+            default: Bindings::video_controller(),
+            profiles: Vec::new(),
+        }
+    }
+}
+
+impl BindingsFile {
+    pub fn load(path: &str) -> Self {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return Self::default(),
+        };
+        match ron::from_str(&contents) {
+            Ok(bindings_file) => bindings_file,
+            Err(err) => {
+                println!("can't parse {}: {}", path, err);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &str) {
+        // a binding per line block, its control and trigger stay on one line
+        let pretty = ron::ser::PrettyConfig::new().with_depth_limit(6);
+        let result = ron::ser::to_string_pretty(self, pretty)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| fs::write(path, contents + "\n").map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            println!("can't save {}: {}", path, err);
+        }
+    }
+
+    // the identity wins, the same controller shows up under different port names
+    // depending on the platform and the usb port
+    fn profile_index(&self, device_name: &str, identity: Option<&DeviceIdentity>) -> Option<usize> {
+        let by_identity = identity.and_then(|identity| {
+            self.profiles.iter().position(|profile| {
+                profile
+                    .identity
+                    .as_ref()
+                    .is_some_and(|identity_match| identity_match.matches(identity))
+            })
+        });
+        let device_name = device_name.to_lowercase();
+        by_identity.or_else(|| {
+            self.profiles
+                .iter()
+                .position(|profile| device_name.contains(&profile.device.to_lowercase()))
+        })
+    }
+
+    // devices are (port name, identity) in port order, with several devices
+    // the first one that has a profile picks it
+    fn devices_profile_index(&self, devices: &[(&str, Option<&DeviceIdentity>)]) -> Option<usize> {
+        devices
+            .iter()
+            .find_map(|(device_name, identity)| self.profile_index(device_name, *identity))
+    }
+
+    // bindings learned for the devices go into their profile,
+    // one is made for the main device when none of them has one
+    pub fn bindings_for_mut(
+        &mut self,
+        devices: &[(&str, Option<&DeviceIdentity>)],
+    ) -> &mut Bindings {
+        let (device_name, identity) = match devices.first() {
+            Some(main_device) => *main_device,
+            None => return &mut self.default,
+        };
+        let index = match self.devices_profile_index(devices) {
+            Some(index) => index,
+            None => {
+                self.profiles.push(DeviceProfile {
+                    device: device_name.to_string(),
+                    identity: identity.map(IdentityMatch::exact),
+                    bindings: self.default.clone(),
+                });
+                self.profiles.len() - 1
+            }
+        };
+        &mut self.profiles[index].bindings
+    }
+
+    pub fn bindings_for(&self, devices: &[(&str, Option<&DeviceIdentity>)]) -> &Bindings {
+        match self.devices_profile_index(devices) {
+            Some(index) => &self.profiles[index].bindings,
+            None => &self.default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> DeviceIdentity {
+        DeviceIdentity {
+            manufacturer: vec![0, 32, 41],
+            family: 1,
+            model: 2,
+            version: [0; 4],
+        }
+    }
+
+    fn bindings_file() -> BindingsFile {
+        let mut bindings_file = BindingsFile::default();
+        for (device, identity) in [("pads", None), ("launch", Some(identity()))].iter() {
+            bindings_file.profiles.push(DeviceProfile {
+                device: device.to_string(),
+                identity: identity.as_ref().map(IdentityMatch::exact),
+                bindings: Bindings::default(),
+            });
+        }
+        bindings_file
+    }
+
+    #[test]
+    fn first_device_with_a_profile_picks_it() {
+        let bindings_file = bindings_file();
+        let identity = identity();
+        let picks = |devices: &[(&str, Option<&DeviceIdentity>)], bindings: &Bindings| {
+            std::ptr::eq(bindings_file.bindings_for(devices), bindings)
+        };
+        assert!(picks(&[], &bindings_file.default));
+        assert!(picks(&[("Keyboard", None)], &bindings_file.default));
+        // the keyboard has no profile, the pads on the second port do
+        assert!(picks(
+            &[("Keyboard", None), ("Pads MIDI 1", None)],
+            &bindings_file.profiles[0].bindings
+        ));
+        // port order decides, the identity only picks the profile of its own device
+        assert!(picks(
+            &[("Pads MIDI 1", None), ("Controller", Some(&identity))],
+            &bindings_file.profiles[0].bindings
+        ));
+        assert!(picks(
+            &[("Controller", Some(&identity)), ("Pads MIDI 1", None)],
+            &bindings_file.profiles[1].bindings
+        ));
+    }
+
+    #[test]
+    fn learning_without_a_profile_makes_one_for_the_main_device() {
+        let mut bindings_file = bindings_file();
+        let identity = DeviceIdentity {
+            model: 3,
+            ..identity()
+        };
+        bindings_file
+            .bindings_for_mut(&[("Keys", Some(&identity)), ("Pads MIDI 1", None)])
+            .bind(GameAction::Jump, ControlId::note(0, 60));
+        // the pads on the second port have a profile, so that one learns
+        assert!(bindings_file.profiles[0]
+            .bindings
+            .actions
+            .contains_key(&GameAction::Jump));
+        bindings_file
+            .bindings_for_mut(&[("Keys", Some(&identity))])
+            .bind(GameAction::Jump, ControlId::note(0, 62));
+        let profile = bindings_file.profiles.last().unwrap();
+        assert_eq!(bindings_file.profiles.len(), 3);
+        assert_eq!(profile.device, "Keys");
+        assert_eq!(profile.identity, Some(IdentityMatch::exact(&identity)));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::bindings::*;
+use crate::constants::*;
+use crate::input::*;
+use crate::resources::*;
+use crate::GameState;
+use async_trait::async_trait;
+use macroquad::prelude::*;
+use macroquad_tantan_toolbox::states::*;
+
+// how long a cc or nrpn is watched before deciding if it's a button or a fader
+const DETECT_TIME: f32 = 0.5f32;
+
+// the control that moved first while learning
+struct Candidate {
+    id: ControlId,
+    values: Vec<u8>,
+    timer: f32,
+}
+
+impl Candidate {
+    // pads sending cc usually only send 0 and 127
+    fn is_button(&self) -> bool {
+        match self.id.kind {
+            ControlKind::Note => true,
+            ControlKind::ControlChange | ControlKind::Nrpn | ControlKind::Rpn => {
+                self.values.iter().all(|v| *v == 0 || *v == 127)
+            }
+            _ => false,
+        }
+    }
+
+    fn is_decided(&self) -> bool {
+        match self.id.kind {
+            ControlKind::ControlChange | ControlKind::Nrpn | ControlKind::Rpn => {
+                self.timer >= DETECT_TIME
+            }
+            _ => true,
+        }
+    }
+}
+
+// "midi learn", pick an action and touch the control that should trigger it
+// faders can also be calibrated by sweeping them from end to end
+pub struct BindingsState {
+    selected: usize,
+    is_learning: bool,
+    candidate: Option<Candidate>,
+    is_calibrating: bool,
+    // range seen so far, None until the fader moves
+    calibration: Option<Calibration>,
+    message: String,
+}
+
+impl BindingsState {
+    pub fn new() -> Self {
+        Self {
+            selected: 0,
+            is_learning: false,
+            candidate: None,
+            is_calibrating: false,
+            calibration: None,
+            message: String::new(),
+        }
+    }
+
+    fn learn(&mut self, dt: f32, shared_data: &mut SharedData) {
+        for (id, value) in shared_data.input.midi.control_updates() {
+            let (id, value) = (*id, value.coarse);
+            match &mut self.candidate {
+                Some(candidate) if candidate.id == id => candidate.values.push(value),
+                Some(_) => {}
+                None => {
+                    self.candidate = Some(Candidate {
+                        id,
+                        values: vec![value],
+                        timer: 0f32,
+                    })
+                }
+            }
+        }
+
+        let candidate = match &mut self.candidate {
+            Some(candidate) => candidate,
+            None => return,
+        };
+        candidate.timer += dt;
+        if !candidate.is_decided() {
+            return;
+        }
+
+        let action = GameAction::ALL[self.selected];
+        let is_button = candidate.is_button();
+        let id = candidate.id;
+        shared_data.device_bindings_mut().bind(action, id);
+        shared_data.bindings_file.save(BINDINGS_PATH);
+        shared_data.apply_bindings_profile();
+
+        self.message = format!(
+            "{:?} bound to {} ({})",
+            action,
+            id,
+            if is_button { "button" } else { "fader" }
+        );
+        if is_button != action.is_button() {
+            self.message.push_str(if action.is_button() {
+                ", expected a button"
+            } else {
+                ", expected a fader"
+            });
+        }
+        self.is_learning = false;
+        self.candidate = None;
+    }
+
+    fn start_calibrating(&mut self, shared_data: &SharedData) {
+        let action = GameAction::ALL[self.selected];
+        match shared_data.input.bindings().actions.get(&action) {
+            Some(binding) if !action.is_button() && !binding.is_relative() => {
+                self.is_calibrating = true;
+                self.calibration = None;
+                self.message.clear();
+            }
+            Some(_) => self.message = format!("{:?} can't be calibrated", action),
+            None => self.message = format!("bind {:?} before calibrating it", action),
+        }
+    }
+
+    fn calibrate(&mut self, shared_data: &mut SharedData) {
+        let action = GameAction::ALL[self.selected];
+        let id = match shared_data.input.bindings().control(action) {
+            Some(id) => id,
+            None => return,
+        };
+        for (_, value) in shared_data
+            .input
+            .midi
+            .control_updates()
+            .iter()
+            .filter(|(update_id, _)| *update_id == id)
+        {
+            match &mut self.calibration {
+                Some(calibration) => calibration.record(value.fraction),
+                None => self.calibration = Some(Calibration::new(value.fraction)),
+            }
+        }
+
+        if !is_key_pressed(KeyCode::Enter) {
+            return;
+        }
+        let calibration = match self.calibration {
+            Some(calibration) if calibration.max > calibration.min => calibration,
+            _ => {
+                self.message = String::from("move the fader from end to end first");
+                return;
+            }
+        };
+        let bindings = shared_data.device_bindings_mut();
+        if let Some(binding) = bindings.actions.get_mut(&action) {
+            binding.calibration = Some(calibration);
+        }
+        shared_data.bindings_file.save(BINDINGS_PATH);
+        shared_data.apply_bindings_profile();
+        self.message = format!(
+            "{:?} calibrated to {:.0} - {:.0}",
+            action,
+            calibration.min * 127f32,
+            calibration.max * 127f32
+        );
+        self.is_calibrating = false;
+    }
+}
+
+#[async_trait]
+impl State<TransitionData, SharedData> for BindingsState {
+    fn on_enter(&mut self, _payload: StateManagerPayload<SharedData>) {}
+
+    async fn on_update(
+        &mut self,
+        _delta_time: f32,
+        payload: &mut StateManagerPayload<SharedData>,
+    ) -> Option<StateManagerCommand<TransitionData, SharedData>> {
+        let shared_data = &mut payload.shared_data;
+        let dt = get_frame_time();
+        shared_data.poll_devices(dt);
+        shared_data.input.update(dt);
+        // keeps counting so the beat is still right when going back to the game
+        let events = shared_data.input.midi.events_this_frame();
+        shared_data.clock.update(dt, events);
+
+        let mut command = None;
+        if self.is_learning {
+            self.learn(dt, shared_data);
+            if is_key_pressed(KeyCode::Escape) {
+                self.is_learning = false;
+                self.candidate = None;
+            }
+        } else if self.is_calibrating {
+            self.calibrate(shared_data);
+            if is_key_pressed(KeyCode::Escape) {
+                self.is_calibrating = false;
+                self.message.clear();
+            }
+        } else {
+            if is_key_pressed(KeyCode::Down) && self.selected + 1 < GameAction::ALL.len() {
+                self.selected += 1;
+            }
+            if is_key_pressed(KeyCode::Up) && self.selected > 0 {
+                self.selected -= 1;
+            }
+            if is_key_pressed(KeyCode::Enter) {
+                self.is_learning = true;
+                self.message.clear();
+            }
+            if is_key_pressed(KeyCode::C) {
+                self.start_calibrating(shared_data);
+            }
+            if is_key_pressed(KeyCode::Backspace) {
+                let action = GameAction::ALL[self.selected];
+                shared_data.device_bindings_mut().unbind(action);
+                shared_data.bindings_file.save(BINDINGS_PATH);
+                shared_data.apply_bindings_profile();
+            }
+            if is_key_pressed(KeyCode::Escape) || is_key_pressed(KeyCode::F2) {
+                command = Some(StateManagerCommand::ChangeStateEx(
+                    Box::new(GameState::new()),
+                    TransitionTime(0.3),
+                    TransitionData::Slide,
+                ));
+            }
+        }
+
+        shared_data.input.flush();
+        command
+    }
+
+    fn on_draw(&mut self, payload: StateManagerPayload<SharedData>) {
+        clear_background(BLACK);
+        let bindings = payload.shared_data.input.bindings();
+        let x = 20f32;
+        let mut y = 24f32;
+        draw_text("BINDINGS", x, y, 24f32, WHITE);
+        y += 20f32;
+        for (i, action) in GameAction::ALL.iter().enumerate() {
+            let color = if i == self.selected { YELLOW } else { WHITE };
+            let control = match bindings.control(*action) {
+                Some(id) => id.to_string(),
+                None => String::from("-"),
+            };
+            draw_text(format!("{:?}", action).as_str(), x, y, 16f32, color);
+            draw_text(control.as_str(), x + 140f32, y, 16f32, color);
+            y += 15f32;
+        }
+        let hint = if self.is_learning {
+            format!(
+                "move a control for {:?}... esc: cancel",
+                GameAction::ALL[self.selected]
+            )
+        } else if self.is_calibrating {
+            let range = match self.calibration {
+                Some(calibration) => format!(
+                    "{:.0} - {:.0}",
+                    calibration.min * 127f32,
+                    calibration.max * 127f32
+                ),
+                None => String::from("-"),
+            };
+            format!(
+                "sweep {:?} end to end ({})... enter: save   esc: cancel",
+                GameAction::ALL[self.selected],
+                range
+            )
+        } else {
+            String::from("enter: learn   c: calibrate   backspace: unbind   esc: back")
+        };
+        draw_text(self.message.as_str(), x, GAME_SIZE.y - 34f32, 16f32, GREEN);
+        draw_text(hint.as_str(), x, GAME_SIZE.y - 16f32, 16f32, GRAY);
+    }
+}
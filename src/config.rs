@@ -0,0 +1,132 @@
+use serde::Deserialize;
+use std::fs;
+
+pub const CONFIG_PATH: &str = "config.ron";
+
+// settings read from config.ron, command line arguments take priority
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    // part of the midi port name to connect to, matched ignoring case
+    pub midi_device: Option<String>,
+    // more devices read at the same time, port 1 onwards in the bindings
+    pub more_midi_devices: Vec<String>,
+    // pads hit softer than this don't count as pressed, 0 to 127
+    pub velocity_threshold: u8,
+    // read cc 32-63 as the fine half of cc 0-31, for devices with 14 bit faders,
+    // off since many devices use them as controls of their own
+    pub pair_14_bit_cc: bool,
+    // member channels of an mpe lower zone, for mpe controllers that don't
+    // announce their zones, 0 waits for the controller to
+    pub mpe_members: u8,
+    // part of the midi output port name to light pads and move faders on,
+    // None uses the output with the same name as the midi device
+    pub feedback_device: Option<String>,
+    // send feedback to a virtual port instead, lets other software see what's sent
+    pub virtual_feedback_port: bool,
+    // make a "midi_game in" port for other software to play the game with
+    // instead of connecting to a device, midi_device is ignored
+    pub virtual_midi_port: bool,
+    // accept rtp-midi sessions on this udp port and the next one, 5004 is the usual one
+    pub rtp_midi_port: Option<u16>,
+    // read osc from controller apps on this udp port, they mostly send to 9000
+    pub osc_port: Option<u16>,
+    // write every midi event to this file, replay it later to reproduce a session
+    pub record: Option<String>,
+    // play a recording back on startup, on top of any live input
+    pub replay: Option<String>,
+    // standard midi file played as if it was a controller, for demos and attract mode
+    pub midi_file: Option<String>,
+    // start the midi file over when it ends
+    pub loop_midi_file: bool,
+    // measure how late midi events reach the game and print a report every few seconds
+    pub latency_diagnostics: bool,
+    // also send notes through the virtual midi port and time them coming back,
+    // turns on the virtual port and the diagnostics
+    pub latency_loopback: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            midi_device: None,
+            more_midi_devices: Vec::new(),
+            velocity_threshold: 0,
+            pair_14_bit_cc: false,
+            mpe_members: 0,
+            feedback_device: None,
+            virtual_feedback_port: false,
+            virtual_midi_port: false,
+            rtp_midi_port: None,
+            osc_port: None,
+            record: None,
+            replay: None,
+            midi_file: None,
+            loop_midi_file: false,
+            latency_diagnostics: false,
+            latency_loopback: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let mut config = Self::load_file(CONFIG_PATH);
+        config.apply_args(std::env::args().skip(1));
+        if config.latency_loopback {
+            config.virtual_midi_port = true;
+            config.latency_diagnostics = true;
+        }
+        config
+    }
+
+    fn load_file(path: &str) -> Self {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return Self::default(),
+        };
+        match ron::from_str(&contents) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("can't parse {}: {}", path, err);
+                Self::default()
+            }
+        }
+    }
+
+    fn apply_args(&mut self, mut args: impl Iterator<Item = String>) {
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--midi-device" => self.midi_device = args.next(),
+                "--add-midi-device" => self.more_midi_devices.extend(args.next()),
+                "--velocity-threshold" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(velocity_threshold) => self.velocity_threshold = velocity_threshold,
+                    None => println!("--velocity-threshold needs a number from 0 to 127"),
+                },
+                "--14-bit-cc" => self.pair_14_bit_cc = true,
+                "--mpe-members" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(mpe_members) => self.mpe_members = mpe_members,
+                    None => println!("--mpe-members needs a number from 0 to 15"),
+                },
+                "--feedback-device" => self.feedback_device = args.next(),
+                "--virtual-feedback-port" => self.virtual_feedback_port = true,
+                "--virtual-midi-port" => self.virtual_midi_port = true,
+                "--rtp-midi-port" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(rtp_midi_port) => self.rtp_midi_port = Some(rtp_midi_port),
+                    None => println!("--rtp-midi-port needs a udp port number"),
+                },
+                "--osc-port" => match args.next().and_then(|value| value.parse().ok()) {
+                    Some(osc_port) => self.osc_port = Some(osc_port),
+                    None => println!("--osc-port needs a udp port number"),
+                },
+                "--record" => self.record = args.next(),
+                "--replay" => self.replay = args.next(),
+                "--midi-file" => self.midi_file = args.next(),
+                "--loop" => self.loop_midi_file = true,
+                "--latency-diagnostics" => self.latency_diagnostics = true,
+                "--latency-loopback" => self.latency_loopback = true,
+                _ => println!("unknown argument: {}", arg),
+            }
+        }
+    }
+}
@@ -5,3 +5,20 @@ pub const GAME_SIZE: Vec2 = const_vec2!([514., 256.]);
 pub const GRAVITY: f32 = 30f32;
 pub const MOVE_SPEED: f32 = 200f32;
 pub const MAX_JUMP_STRENGTH: f32 = 12f32;
+// pressing a held move key all the way down runs this much faster, needs aftertouch
+pub const PRESSURE_RUN_BOOST: f32 = 1f32;
+// bending the held shoot note this many semitones, or sliding all the way along it,
+// aims as far up or down as the gun goes, needs an mpe controller
+pub const AIM_BEND_RANGE: f32 = 2f32;
+pub const MAX_AIM_ANGLE: f32 = std::f32::consts::FRAC_PI_4;
+
+// the water faders go from still to these
+pub const MAX_WATER_SPEED: f32 = 0.5f32;
+pub const MAX_WATER_STRENGTH: f32 = 0.3f32;
+// water before the faders are touched
+pub const WATER_SPEED: f32 = 0.025f32;
+pub const WATER_STRENGTH: f32 = 0.02f32;
+// while a sequencer plays, the waves get this much stronger on every beat
+pub const BEAT_WATER_SWELL: f32 = 1f32;
+// tempo the animation frame rates are made for, midi clock speeds them up or slows them down
+pub const ANIMATION_BPM: f32 = 120f32;
@@ -0,0 +1,208 @@
+use crate::input::*;
+use crate::midi_message::*;
+use std::collections::HashMap;
+
+const CC_DATA_ENTRY: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_DATA_INCREMENT: u8 = 96;
+const CC_DATA_DECREMENT: u8 = 97;
+const CC_NRPN_LSB: u8 = 98;
+const CC_NRPN_MSB: u8 = 99;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+// cc 0-31 are paired with an lsb 32 numbers higher
+const CC_LSB_OFFSET: u8 = 32;
+const MAX_14_BIT: f32 = 16383f32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ControlValue {
+    // 7 bit value, what buttons and velocity look at
+    pub coarse: u8,
+    // full resolution, 0 to 1
+    pub fraction: f32,
+}
+
+impl ControlValue {
+    pub fn from_7_bit(value: u8) -> Self {
+        Self {
+            coarse: value,
+            fraction: value as f32 / 127f32,
+        }
+    }
+
+    pub fn from_14_bit(value: u16) -> Self {
+        Self {
+            coarse: (value >> 7) as u8,
+            fraction: value as f32 / MAX_14_BIT,
+        }
+    }
+
+    fn from_msb_lsb(msb: u8, lsb: u8) -> Self {
+        Self::from_14_bit((msb as u16) << 7 | lsb as u16)
+    }
+}
+
+// parameter and data entry state of one channel
+#[derive(Default)]
+struct ChannelState {
+    msb: [u8; 32],
+    // set once an lsb arrived for the controller, until then it's treated as 7 bit
+    is_paired: [bool; 32],
+    nrpn: (u8, u8),
+    rpn: (u8, u8),
+    parameter: Option<(ControlKind, u16)>,
+    data_msb: u8,
+    data_lsb: u8,
+    is_data_fine: bool,
+}
+
+impl ChannelState {
+    fn select_parameter(&mut self, kind: ControlKind, (msb, lsb): (u8, u8)) {
+        // rpn 127/127 is the "null" parameter, data entry goes back to being a plain cc
+        self.parameter = if kind == ControlKind::Rpn && msb == 127 && lsb == 127 {
+            None
+        } else {
+            Some((kind, (msb as u16) << 7 | lsb as u16))
+        };
+        self.is_data_fine = false;
+    }
+
+    fn data_value(&self) -> ControlValue {
+        if self.is_data_fine {
+            ControlValue::from_msb_lsb(self.data_msb, self.data_lsb)
+        } else {
+            ControlValue::from_7_bit(self.data_msb)
+        }
+    }
+}
+
+// turns messages into control updates at the highest resolution the device sends:
+// 14 bit cc pairs, nrpn/rpn data entry and pitch bend
+pub struct ControlDecoder {
+    channels: HashMap<(u8, u8), ChannelState>,
+    pair_14_bit_cc: bool,
+}
+
+impl ControlDecoder {
+    pub fn new() -> Self {
+        Self {
+            channels: HashMap::new(),
+            pair_14_bit_cc: false,
+        }
+    }
+
+    // off by default, many devices use cc 32-63 as controls of their own
+    pub fn set_pair_14_bit_cc(&mut self, pair_14_bit_cc: bool) {
+        self.pair_14_bit_cc = pair_14_bit_cc;
+    }
+
+    pub fn clear(&mut self) {
+        self.channels.clear();
+    }
+
+    pub fn clear_port(&mut self, port: u8) {
+        self.channels
+            .retain(|(channel_port, _), _| *channel_port != port);
+    }
+
+    pub fn decode(&mut self, port: u8, message: &MidiMessage) -> Option<(ControlId, ControlValue)> {
+        match *message {
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => self.decode_cc(port, channel, controller, value),
+            MidiMessage::PitchBend { channel, value } => Some((
+                ControlId::new(port, channel, ControlKind::PitchBend, 0),
+                ControlValue::from_14_bit(value),
+            )),
+            _ => ControlId::from_message(port, message)
+                .map(|(id, value)| (id, ControlValue::from_7_bit(value))),
+        }
+    }
+
+    fn decode_cc(
+        &mut self,
+        port: u8,
+        channel: u8,
+        controller: u8,
+        value: u8,
+    ) -> Option<(ControlId, ControlValue)> {
+        let pair_14_bit_cc = self.pair_14_bit_cc;
+        let state = self.channels.entry((port, channel)).or_default();
+        let cc = |controller: u8, value: ControlValue| {
+            Some((
+                ControlId::new(port, channel, ControlKind::ControlChange, controller as u16),
+                value,
+            ))
+        };
+
+        if let Some((kind, number)) = state.parameter {
+            let parameter = ControlId::new(port, channel, kind, number);
+            match controller {
+                CC_DATA_ENTRY => {
+                    state.data_msb = value;
+                    state.data_lsb = 0;
+                    return Some((parameter, state.data_value()));
+                }
+                CC_DATA_ENTRY_LSB => {
+                    state.data_lsb = value;
+                    state.is_data_fine = true;
+                    return Some((parameter, state.data_value()));
+                }
+                CC_DATA_INCREMENT => {
+                    state.data_msb = (state.data_msb + 1).min(127);
+                    return Some((parameter, state.data_value()));
+                }
+                CC_DATA_DECREMENT => {
+                    state.data_msb = state.data_msb.saturating_sub(1);
+                    return Some((parameter, state.data_value()));
+                }
+                _ => {}
+            }
+        }
+
+        match controller {
+            CC_NRPN_MSB => {
+                state.nrpn.0 = value;
+                let nrpn = state.nrpn;
+                state.select_parameter(ControlKind::Nrpn, nrpn);
+            }
+            CC_NRPN_LSB => {
+                state.nrpn.1 = value;
+                let nrpn = state.nrpn;
+                state.select_parameter(ControlKind::Nrpn, nrpn);
+            }
+            CC_RPN_MSB => {
+                state.rpn.0 = value;
+                let rpn = state.rpn;
+                state.select_parameter(ControlKind::Rpn, rpn);
+            }
+            CC_RPN_LSB => {
+                state.rpn.1 = value;
+                let rpn = state.rpn;
+                state.select_parameter(ControlKind::Rpn, rpn);
+            }
+            0..=31 if pair_14_bit_cc => {
+                let index = controller as usize;
+                // a new msb starts a new value, the lsb follows it
+                state.msb[index] = value;
+                return if state.is_paired[index] {
+                    cc(controller, ControlValue::from_msb_lsb(value, 0))
+                } else {
+                    cc(controller, ControlValue::from_7_bit(value))
+                };
+            }
+            32..=63 if pair_14_bit_cc => {
+                let index = (controller - CC_LSB_OFFSET) as usize;
+                state.is_paired[index] = true;
+                return cc(
+                    controller - CC_LSB_OFFSET,
+                    ControlValue::from_msb_lsb(state.msb[index], value),
+                );
+            }
+            _ => {}
+        }
+        cc(controller, ControlValue::from_7_bit(value))
+    }
+}
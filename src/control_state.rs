@@ -0,0 +1,611 @@
+use crate::bindings::*;
+use crate::control_decoder::*;
+use crate::event_queue::*;
+use crate::expression::*;
+use crate::input::*;
+use crate::midi_message::*;
+use std::collections::HashMap;
+
+// control changes used as buttons count as down from here, like a sustain pedal
+const SWITCH_THRESHOLD: u8 = 64;
+
+fn to_fraction(v: u8) -> f32 {
+    v as f32 / 127f32
+}
+
+// everything a control did during one frame, derived from the full event stream
+// so nothing is lost when it changes several times between two frames
+//
+// buttons follow this state machine:
+//
+//   Up   --note on with velocity >= threshold, or cc >= 64-->  Down
+//   Down --note off, note on with velocity 0, or cc < 64-->    Up
+//
+// pressed and released are edges, only true on the frame the transition arrived.
+// a button tapped within one frame is both pressed and released on that frame.
+// held is true on every following frame for as long as the button stays down.
+#[derive(Clone, Debug, Default)]
+pub struct ControlState {
+    // value at the end of the previous frame, None until the control is first seen
+    previous_value: Option<ControlValue>,
+    // every value received this frame, oldest first
+    values: Vec<ControlValue>,
+    // button state at the end of the previous frame
+    was_down: bool,
+    // every time the button went down (true) or up (false) this frame
+    transitions: Vec<bool>,
+    // velocity of the last press
+    velocity: u8,
+    // trigger thresholds that can fire again, cleared when reached until the
+    // fader moves back past the hysteresis
+    is_low_armed: bool,
+    is_high_armed: bool,
+    // trigger thresholds reached this frame
+    reached_low: bool,
+    reached_high: bool,
+}
+
+impl ControlState {
+    fn last_value(&self) -> Option<ControlValue> {
+        self.values.last().copied().or(self.previous_value)
+    }
+
+    // 7 bit value
+    pub fn value(&self) -> Option<u8> {
+        self.last_value().map(|value| value.coarse)
+    }
+
+    // full resolution when the device sends 14 bit values
+    pub fn fraction(&self) -> Option<f32> {
+        self.last_value().map(|value| value.fraction)
+    }
+
+    // every value this frame, oldest first
+    pub fn values(&self) -> &[ControlValue] {
+        &self.values
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.transitions.last().copied().unwrap_or(self.was_down)
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.was_down && self.is_down()
+    }
+
+    // true even if the button was tapped and let go within the frame
+    pub fn is_pressed(&self) -> bool {
+        self.transitions.iter().any(|is_down| *is_down)
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.transitions.iter().any(|is_down| !*is_down)
+    }
+
+    pub fn velocity(&self) -> f32 {
+        to_fraction(self.velocity)
+    }
+
+    // checks every step the control took this frame, so sweeping past the limit
+    // and back before the next frame still counts
+    pub fn reached_limit(&self, fraction: f32, limit: SliderLimitCheck) -> bool {
+        let mut previous = self.previous_value.map(|value| value.fraction);
+        for value in self.values.iter().map(|value| value.fraction) {
+            if let Some(previous) = previous {
+                let crossed = match limit {
+                    SliderLimitCheck::Lower => value <= fraction && previous > fraction,
+                    SliderLimitCheck::Higher => value >= fraction && previous < fraction,
+                };
+                if crossed {
+                    return true;
+                }
+            }
+            previous = Some(value);
+        }
+        false
+    }
+
+    // like reached_limit at the trigger thresholds of the binding, with its hysteresis
+    pub fn reached_trigger(&self, limit: SliderLimitCheck) -> bool {
+        match limit {
+            SliderLimitCheck::Lower => self.reached_low,
+            SliderLimitCheck::Higher => self.reached_high,
+        }
+    }
+
+    fn begin_frame(&mut self) {
+        self.previous_value = self.last_value();
+        self.values.clear();
+        self.was_down = self.is_down();
+        self.transitions.clear();
+        self.reached_low = false;
+        self.reached_high = false;
+    }
+
+    fn push_value(&mut self, value: ControlValue, trigger: &Trigger) {
+        if let Some(previous) = self.last_value().map(|value| value.fraction) {
+            let fraction = value.fraction;
+            if self.is_low_armed && fraction <= trigger.low && previous > trigger.low {
+                self.reached_low = true;
+                self.is_low_armed = false;
+            }
+            if self.is_high_armed && fraction >= trigger.high && previous < trigger.high {
+                self.reached_high = true;
+                self.is_high_armed = false;
+            }
+        }
+        if value.fraction >= trigger.low + trigger.hysteresis {
+            self.is_low_armed = true;
+        }
+        if value.fraction <= trigger.high - trigger.hysteresis {
+            self.is_high_armed = true;
+        }
+        self.values.push(value);
+    }
+
+    fn set_down(&mut self, is_down: bool, velocity: u8) {
+        if is_down == self.is_down() {
+            return;
+        }
+        self.transitions.push(is_down);
+        if is_down {
+            self.velocity = velocity;
+        }
+    }
+
+    // relative encoders move a virtual value instead of reporting a position
+    fn accumulate(&self, encoder: &Binding, value: ControlValue) -> ControlValue {
+        // starts centered so it can be turned either way
+        let current = self.last_value().map_or(0.5f32, |value| value.fraction);
+        let moved = current + encoder.encoder.delta(value.coarse) as f32 * encoder.sensitivity;
+        let fraction = if encoder.wrap {
+            moved.rem_euclid(1f32)
+        } else {
+            moved.clamp(0f32, 1f32)
+        };
+        ControlValue {
+            coarse: (fraction * 127f32).round() as u8,
+            fraction,
+        }
+    }
+}
+
+pub struct ControlStates {
+    states: HashMap<ControlId, ControlState>,
+    decoder: ControlDecoder,
+    // how each bound control is read, unbound controls are read as they are
+    bindings: HashMap<ControlId, Binding>,
+    // every control that changed this frame as decoded, before any binding is applied
+    updates: Vec<(ControlId, ControlValue)>,
+    // note ons softer than this are ignored
+    velocity_threshold: u8,
+    expression: ExpressionTracker,
+    // channels holding each note down, mpe puts the same note of a zone on
+    // several member channels and it's up once all of them let go
+    held_channels: HashMap<ControlId, u16>,
+}
+
+impl ControlStates {
+    pub fn new() -> Self {
+        Self {
+            states: HashMap::new(),
+            decoder: ControlDecoder::new(),
+            bindings: HashMap::new(),
+            updates: Vec::new(),
+            velocity_threshold: 0,
+            expression: ExpressionTracker::new(),
+            held_channels: HashMap::new(),
+        }
+    }
+
+    pub fn set_mpe_members(&mut self, members: u8) {
+        self.expression.set_default_lower_zone(members);
+    }
+
+    pub fn expression(&self) -> &ExpressionTracker {
+        &self.expression
+    }
+
+    pub fn set_pair_14_bit_cc(&mut self, pair_14_bit_cc: bool) {
+        self.decoder.set_pair_14_bit_cc(pair_14_bit_cc);
+    }
+
+    pub fn set_bindings(&mut self, bindings: &Bindings) {
+        self.bindings = bindings
+            .actions
+            .values()
+            .map(|binding| (binding.control, binding.clone()))
+            .collect();
+    }
+
+    pub fn updates(&self) -> &[(ControlId, ControlValue)] {
+        &self.updates
+    }
+
+    pub fn set_velocity_threshold(&mut self, velocity_threshold: u8) {
+        self.velocity_threshold = velocity_threshold;
+    }
+
+    // call before applying the events of a new frame
+    pub fn begin_frame(&mut self) {
+        for state in self.states.values_mut() {
+            state.begin_frame();
+        }
+        self.updates.clear();
+    }
+
+    pub fn apply(&mut self, event: &InputEvent) {
+        // soft hits are dropped before they become held notes with expression
+        if let MidiMessage::NoteOn { velocity, .. } = event.message {
+            if velocity > 0 && velocity < self.velocity_threshold {
+                return;
+            }
+        }
+        self.expression.apply(event);
+        let (id, value) = match self.decoder.decode(event.port, &event.message) {
+            Some((id, value)) => (self.expression.zone_control(id), value),
+            None => return,
+        };
+        // note on with velocity 0 is how most devices send note off
+        let is_down = match event.message {
+            MidiMessage::NoteOn {
+                channel, velocity, ..
+            } => self.hold(id, channel, velocity > 0),
+            MidiMessage::NoteOff { channel, .. } => self.hold(id, channel, false),
+            _ => value.coarse >= SWITCH_THRESHOLD,
+        };
+        self.updates.push((id, value));
+        let state = self.states.entry(id).or_default();
+        let binding = self.bindings.get(&id);
+        let value = match binding {
+            Some(binding) if binding.is_relative() => state.accumulate(binding, value),
+            // encoders are left unshaped, the shaped value would be accumulated again
+            Some(binding) => {
+                let fraction = binding.shape(value.fraction);
+                ControlValue {
+                    coarse: (fraction * 127f32).round() as u8,
+                    fraction,
+                }
+            }
+            None => value,
+        };
+        let trigger = binding.map(|binding| binding.trigger).unwrap_or_default();
+        state.push_value(value, &trigger);
+        state.set_down(is_down, value.coarse);
+    }
+
+    // whether the note is still down on any channel after this one pressed or let go of it
+    fn hold(&mut self, id: ControlId, channel: u8, is_down: bool) -> bool {
+        let channels = self.held_channels.entry(id).or_default();
+        if is_down {
+            *channels |= 1 << channel;
+        } else {
+            *channels &= !(1 << channel);
+        }
+        *channels != 0
+    }
+
+    pub fn get(&self, id: ControlId) -> Option<&ControlState> {
+        self.states.get(&id)
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.decoder.clear();
+        self.expression.clear();
+        self.held_channels.clear();
+    }
+
+    // when one of several devices goes away
+    pub fn clear_port(&mut self, port: u8) {
+        self.states.retain(|id, _| id.port != port);
+        self.decoder.clear_port(port);
+        self.expression.clear_port(port);
+        self.held_channels.retain(|id, _| id.port != port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAD: ControlId = ControlId::note(9, 41);
+    const FADER: ControlId = ControlId::cc(0, 1);
+
+    // runs one frame worth of raw midi bytes through the parser and the state machine
+    fn frame(states: &mut ControlStates, parser: &mut MidiParser, messages: &[&[u8]]) {
+        states.begin_frame();
+        for bytes in messages {
+            for message in parser.parse(bytes) {
+                states.apply(&InputEvent {
+                    timestamp: 0,
+                    port: 0,
+                    message,
+                    received: None,
+                });
+            }
+        }
+    }
+
+    fn pad(states: &ControlStates) -> (bool, bool, bool) {
+        match states.get(PAD) {
+            Some(state) => (state.is_pressed(), state.is_held(), state.is_released()),
+            None => (false, false, false),
+        }
+    }
+
+    #[test]
+    fn untouched_button_is_never_released() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        frame(&mut states, &mut parser, &[]);
+        assert_eq!(pad(&states), (false, false, false));
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 64]]);
+        assert_eq!(pad(&states), (false, false, false));
+    }
+
+    #[test]
+    fn press_hold_release() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        frame(&mut states, &mut parser, &[&[0x99, 41, 100]]);
+        assert_eq!(pad(&states), (true, false, false));
+        frame(&mut states, &mut parser, &[]);
+        assert_eq!(pad(&states), (false, true, false));
+        frame(&mut states, &mut parser, &[&[0x89, 41, 0]]);
+        assert_eq!(pad(&states), (false, false, true));
+        frame(&mut states, &mut parser, &[]);
+        assert_eq!(pad(&states), (false, false, false));
+    }
+
+    #[test]
+    fn note_on_with_zero_velocity_releases() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        frame(&mut states, &mut parser, &[&[0x99, 41, 100]]);
+        // running status, second note on has velocity 0
+        frame(&mut states, &mut parser, &[&[41, 0]]);
+        assert_eq!(pad(&states), (false, false, true));
+    }
+
+    #[test]
+    fn tap_within_one_frame_is_pressed_and_released() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        frame(
+            &mut states,
+            &mut parser,
+            &[
+                &[0x99, 41, 100],
+                &[0x89, 41, 0],
+                &[0x99, 41, 80],
+                &[0x89, 41, 0],
+            ],
+        );
+        assert_eq!(pad(&states), (true, false, true));
+        frame(&mut states, &mut parser, &[]);
+        assert_eq!(pad(&states), (false, false, false));
+    }
+
+    #[test]
+    fn soft_hits_below_threshold_are_ignored() {
+        let mut states = ControlStates::new();
+        states.set_velocity_threshold(20);
+        let mut parser = MidiParser::new();
+        frame(&mut states, &mut parser, &[&[0x99, 41, 10], &[0x89, 41, 0]]);
+        assert_eq!(pad(&states), (false, false, false));
+        frame(&mut states, &mut parser, &[&[0x99, 41, 127]]);
+        assert_eq!(pad(&states), (true, false, false));
+        assert_eq!(states.get(PAD).unwrap().velocity(), 1f32);
+    }
+
+    #[test]
+    fn soft_hits_leave_no_held_note_behind() {
+        let mut states = ControlStates::new();
+        states.set_velocity_threshold(20);
+        let mut parser = MidiParser::new();
+        frame(&mut states, &mut parser, &[&[0x99, 41, 10, 0xD9, 127]]);
+        assert_eq!(states.expression().note(PAD), None);
+    }
+
+    #[test]
+    fn mpe_note_is_down_until_every_member_channel_lets_go() {
+        let mut states = ControlStates::new();
+        states.set_mpe_members(15);
+        let mut parser = MidiParser::new();
+        let note = ControlId::note(0, 60);
+        // the same note on two member channels is one control
+        frame(
+            &mut states,
+            &mut parser,
+            &[&[0x91, 60, 100], &[0x92, 60, 100]],
+        );
+        assert!(states.get(note).unwrap().is_pressed());
+        frame(&mut states, &mut parser, &[&[0x81, 60, 0]]);
+        let state = states.get(note).unwrap();
+        assert!(state.is_held() && !state.is_released());
+        frame(&mut states, &mut parser, &[&[0x92, 60, 0]]);
+        assert!(states.get(note).unwrap().is_released());
+    }
+
+    #[test]
+    fn same_number_on_other_channel_or_kind_is_another_control() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        frame(
+            &mut states,
+            &mut parser,
+            &[&[0x90, 41, 100], &[0xB9, 41, 127]],
+        );
+        assert_eq!(pad(&states), (false, false, false));
+    }
+
+    #[test]
+    fn fader_sweep_within_a_frame_reaches_limit() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 10]]);
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 120], &[0xB0, 1, 10]]);
+        let state = states.get(FADER).unwrap();
+        assert!(state.reached_limit(0.7, SliderLimitCheck::Higher));
+        assert!(state.reached_limit(0.3, SliderLimitCheck::Lower));
+        assert_eq!(state.value(), Some(10));
+    }
+
+    #[test]
+    fn fourteen_bit_cc_pairs_msb_with_lsb() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        // off by default, cc 33 is a button of its own on many controllers
+        frame(&mut states, &mut parser, &[&[0xB0, 33, 127]]);
+        assert!(states.get(ControlId::cc(0, 33)).unwrap().is_pressed());
+        states.clear();
+        states.set_pair_14_bit_cc(true);
+        // plain 7 bit until an lsb shows up
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 127]]);
+        assert_eq!(states.get(FADER).unwrap().fraction(), Some(1f32));
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 64], &[0xB0, 33, 64]]);
+        let fraction = states.get(FADER).unwrap().fraction().unwrap();
+        assert_eq!(fraction, (64 * 128 + 64) as f32 / 16383f32);
+        assert!(states.get(ControlId::cc(0, 33)).is_none());
+    }
+
+    #[test]
+    fn nrpn_data_entry_becomes_its_own_control() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        frame(
+            &mut states,
+            &mut parser,
+            &[
+                &[0xB0, 99, 1],
+                &[0xB0, 98, 2],
+                &[0xB0, 6, 127],
+                &[0xB0, 38, 127],
+            ],
+        );
+        let nrpn = ControlId::new(0, 0, ControlKind::Nrpn, 1 << 7 | 2);
+        assert_eq!(states.get(nrpn).unwrap().fraction(), Some(1f32));
+        assert!(states.get(ControlId::cc(0, 6)).is_none());
+    }
+
+    #[test]
+    fn relative_encoder_accumulates_and_clamps() {
+        let mut states = ControlStates::new();
+        let mut bindings = Bindings::default();
+        bindings.bind(GameAction::CameraX, ControlId::cc(0, 6));
+        let binding = bindings.actions.get_mut(&GameAction::CameraX).unwrap();
+        binding.encoder = EncoderMode::TwosComplement;
+        binding.sensitivity = 0.25f32;
+        states.set_bindings(&bindings);
+        let mut parser = MidiParser::new();
+        let camera = ControlId::cc(0, 6);
+        frame(&mut states, &mut parser, &[&[0xB0, 6, 1]]);
+        assert_eq!(states.get(camera).unwrap().fraction(), Some(0.75f32));
+        frame(
+            &mut states,
+            &mut parser,
+            &[&[0xB0, 6, 127], &[0xB0, 6, 126]],
+        );
+        assert_eq!(states.get(camera).unwrap().fraction(), Some(0f32));
+        frame(&mut states, &mut parser, &[&[0xB0, 6, 127]]);
+        assert_eq!(states.get(camera).unwrap().fraction(), Some(0f32));
+    }
+
+    #[test]
+    fn clear_port_forgets_only_that_port() {
+        let mut states = ControlStates::new();
+        let apply = |states: &mut ControlStates, port: u8, bytes: &[u8]| {
+            for message in MidiParser::new().parse(bytes) {
+                states.apply(&InputEvent {
+                    timestamp: 0,
+                    port,
+                    message,
+                    received: None,
+                });
+            }
+        };
+        // a pad held and nrpn 1/2 selected on both ports
+        for port in 0..2 {
+            apply(&mut states, port, &[0x99, 41, 100, 0xB0, 99, 1, 98, 2]);
+        }
+        states.clear_port(1);
+        let pad = |port| ControlId { port, ..PAD };
+        assert!(states.get(pad(0)).is_some());
+        assert!(states.get(pad(1)).is_none());
+        assert!(states.expression().note(pad(0)).is_some());
+        assert!(states.expression().note(pad(1)).is_none());
+        // the cleared port forgot its parameter, data entry is a plain cc again
+        for port in 0..2 {
+            apply(&mut states, port, &[0xB0, 6, 127]);
+        }
+        let nrpn = |port| ControlId::new(port, 0, ControlKind::Nrpn, 1 << 7 | 2);
+        assert!(states.get(nrpn(0)).is_some());
+        assert!(states.get(nrpn(1)).is_none());
+        assert!(states
+            .get(ControlId::new(1, 0, ControlKind::ControlChange, 6))
+            .is_some());
+    }
+
+    #[test]
+    fn pitch_bend_keeps_14_bits() {
+        let mut states = ControlStates::new();
+        let mut parser = MidiParser::new();
+        frame(&mut states, &mut parser, &[&[0xE0, 1, 64]]);
+        let pitch_bend = ControlId::new(0, 0, ControlKind::PitchBend, 0);
+        let fraction = states.get(pitch_bend).unwrap().fraction().unwrap();
+        assert_eq!(fraction, 8193f32 / 16383f32);
+    }
+
+    #[test]
+    fn calibration_and_dead_zone_stretch_to_the_full_range() {
+        let mut states = ControlStates::new();
+        let mut bindings = Bindings::default();
+        bindings.bind(GameAction::JumpStrength, FADER);
+        let binding = bindings.actions.get_mut(&GameAction::JumpStrength).unwrap();
+        binding.calibration = Some(Calibration {
+            min: 4f32 / 127f32,
+            max: 123f32 / 127f32,
+        });
+        binding.dead_zone = 0.05f32;
+        states.set_bindings(&bindings);
+        let mut parser = MidiParser::new();
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 5]]);
+        assert_eq!(states.get(FADER).unwrap().fraction(), Some(0f32));
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 122]]);
+        assert_eq!(states.get(FADER).unwrap().fraction(), Some(1f32));
+        assert_eq!(states.updates(), &[(FADER, ControlValue::from_7_bit(122))]);
+    }
+
+    #[test]
+    fn trigger_does_not_refire_until_it_moves_past_the_hysteresis() {
+        let mut states = ControlStates::new();
+        let mut bindings = Bindings::default();
+        bindings.bind(GameAction::Latch, FADER);
+        bindings
+            .actions
+            .get_mut(&GameAction::Latch)
+            .unwrap()
+            .trigger = Trigger {
+            low: 0.3f32,
+            high: 0.7f32,
+            hysteresis: 0.1f32,
+        };
+        states.set_bindings(&bindings);
+        let mut parser = MidiParser::new();
+        let reached_high = |states: &ControlStates| {
+            states
+                .get(FADER)
+                .unwrap()
+                .reached_trigger(SliderLimitCheck::Higher)
+        };
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 64]]);
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 90]]);
+        assert!(reached_high(&states));
+        // jitter around the threshold
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 88], &[0xB0, 1, 90]]);
+        assert!(!reached_high(&states));
+        frame(&mut states, &mut parser, &[&[0xB0, 1, 70], &[0xB0, 1, 90]]);
+        assert!(reached_high(&states));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::constants::*;
+use crate::resources::*;
+use async_trait::async_trait;
+use macroquad::prelude::*;
+use macroquad_tantan_toolbox::states::*;
+
+// lets the player choose which midi ports to use before the game starts
+// skipped when a device from config.ron or --midi-device is already selected
+pub struct DevicePickerState {
+    into_state: Option<Box<dyn State<TransitionData, SharedData>>>,
+    port_names: Vec<String>,
+    selected: usize,
+    // indices of the ports picked for playing with several devices, the order
+    // they're picked in is their port number in the bindings
+    picked: Vec<usize>,
+}
+
+impl DevicePickerState {
+    pub fn new(into_state: Box<dyn State<TransitionData, SharedData>>) -> Self {
+        Self {
+            into_state: Some(into_state),
+            port_names: Vec::new(),
+            selected: 0,
+            picked: Vec::new(),
+        }
+    }
+
+    fn leave(&mut self) -> Option<StateManagerCommand<TransitionData, SharedData>> {
+        // unwrap should be safe, we only leave once
+        let into_state = self.into_state.take().unwrap();
+        Some(StateManagerCommand::ChangeStateEx(
+            into_state,
+            TransitionTime(0.3),
+            TransitionData::Slide,
+        ))
+    }
+}
+
+#[async_trait]
+impl State<TransitionData, SharedData> for DevicePickerState {
+    fn on_enter(&mut self, payload: StateManagerPayload<SharedData>) {
+        self.port_names = payload.shared_data.input.midi.port_names();
+    }
+
+    async fn on_update(
+        &mut self,
+        _delta_time: f32,
+        payload: &mut StateManagerPayload<SharedData>,
+    ) -> Option<StateManagerCommand<TransitionData, SharedData>> {
+        if self.into_state.is_none() {
+            return None;
+        }
+        let shared_data = &mut payload.shared_data;
+        let midi = &mut shared_data.input.midi;
+        if midi.has_devices() {
+            midi.connect();
+            shared_data.apply_bindings_profile();
+            return self.leave();
+        }
+
+        if is_key_pressed(KeyCode::R) {
+            self.port_names = midi.port_names();
+            self.selected = 0;
+            self.picked.clear();
+        }
+        if is_key_pressed(KeyCode::Down) && self.selected + 1 < self.port_names.len() {
+            self.selected += 1;
+        }
+        if is_key_pressed(KeyCode::Up) && self.selected > 0 {
+            self.selected -= 1;
+        }
+        if is_key_pressed(KeyCode::Space) && self.selected < self.port_names.len() {
+            match self.picked.iter().position(|index| *index == self.selected) {
+                Some(position) => {
+                    self.picked.remove(position);
+                }
+                None => self.picked.push(self.selected),
+            }
+        }
+        if is_key_pressed(KeyCode::Enter) {
+            if self.picked.is_empty() {
+                self.picked.push(self.selected);
+            }
+            // by the name shown, the ports may have moved since the list was read
+            for index in self.picked.iter() {
+                if let Some(name) = self.port_names.get(*index) {
+                    midi.add_port_by_name(name);
+                }
+            }
+            if midi.has_devices() {
+                midi.connect();
+                shared_data.apply_bindings_profile();
+                return self.leave();
+            }
+            self.picked.clear();
+        }
+        // play without a controller, or only over the network, the default profile
+        // still drives the virtual feedback port
+        if is_key_pressed(KeyCode::Escape) {
+            shared_data.apply_bindings_profile();
+            return self.leave();
+        }
+        None
+    }
+
+    fn on_draw(&mut self, _payload: StateManagerPayload<SharedData>) {
+        clear_background(BLACK);
+        let x = 20f32;
+        let mut y = 30f32;
+        draw_text("SELECT MIDI DEVICES", x, y, 30f32, WHITE);
+        y += 30f32;
+        if self.port_names.is_empty() {
+            draw_text("no midi devices found", x, y, 20f32, GRAY);
+            y += 20f32;
+        }
+        for (i, port_name) in self.port_names.iter().enumerate() {
+            let color = if i == self.selected { YELLOW } else { WHITE };
+            // picked ports show the port number the bindings know them by
+            if let Some(port) = self.picked.iter().position(|index| *index == i) {
+                draw_text(format!("{}", port).as_str(), x, y, 20f32, SKYBLUE);
+            }
+            draw_text(port_name, x + 20f32, y, 20f32, color);
+            y += 20f32;
+        }
+        draw_text(
+            "up/down + enter: connect   space: pick several   r: rescan",
+            x,
+            GAME_SIZE.y - 36f32,
+            16f32,
+            GRAY,
+        );
+        draw_text(
+            "esc: play without device",
+            x,
+            GAME_SIZE.y - 20f32,
+            16f32,
+            GRAY,
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use crate::midi_message::*;
+use crossbeam_queue::ArrayQueue;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// enough for a few frames of a busy controller
+pub const EVENT_QUEUE_CAPACITY: usize = 1024;
+// sysex is rare, only device inquiry replies are read
+pub const SYSEX_QUEUE_CAPACITY: usize = 16;
+
+#[derive(Eq, Clone, Debug, Copy, PartialEq)]
+pub struct InputEvent {
+    // microseconds, as reported by midir
+    pub timestamp: u64,
+    pub port: u8,
+    pub message: MidiMessage,
+    // when the midi thread got it, None for events that didn't come from a device
+    pub received: Option<Instant>,
+}
+
+// bounded lock-free queue between the midi threads and the game thread,
+// clones share the same queue so every producer gets its own handle
+#[derive(Clone)]
+pub struct EventQueue<T = InputEvent> {
+    queue: Arc<ArrayQueue<T>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        Self::with_capacity(EVENT_QUEUE_CAPACITY)
+    }
+}
+
+impl<T> EventQueue<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            queue: Arc::new(ArrayQueue::new(capacity)),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // never blocks, when the game falls behind new events are dropped and counted
+    pub fn push(&self, event: T) {
+        if self.queue.push(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn drain_into(&self, events: &mut Vec<T>) {
+        while let Some(event) = self.queue.pop() {
+            events.push(event);
+        }
+    }
+
+    // total since the queue was created
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
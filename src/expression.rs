@@ -0,0 +1,328 @@
+use crate::event_queue::*;
+use crate::input::*;
+use crate::midi_message::*;
+use std::collections::HashMap;
+
+const CC_TIMBRE: u8 = 74;
+const CC_DATA_ENTRY: u8 = 6;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+const RPN_PITCH_BEND_RANGE: (u8, u8) = (0, 0);
+// mpe configuration message, data entry is how many member channels the zone gets
+const RPN_MPE_CONFIGURATION: (u8, u8) = (0, 6);
+const LOWER_ZONE_MASTER: u8 = 0;
+const UPPER_ZONE_MASTER: u8 = 15;
+// semitones, mpe sets member channels to 48 and everything else stays at the usual 2
+const MEMBER_BEND_RANGE: f32 = 48f32;
+const DEFAULT_BEND_RANGE: f32 = 2f32;
+
+// what a held note is doing right now
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteExpression {
+    // 0 to 1, poly pressure of the note or else the pressure of its channel,
+    // which on an mpe member channel is the note's own
+    pub pressure: f32,
+    // semitones, its own bend plus the bend of its zone's master channel
+    pub pitch_bend: f32,
+    // cc 74, 0 to 1, 0.5 until the controller sends it
+    pub timbre: f32,
+}
+
+struct HeldNote {
+    channel: u8,
+    note: u8,
+    poly_pressure: Option<f32>,
+}
+
+// expression of every channel of one port, mpe sends it before the note on
+// so a new note starts out with the values of its channel
+struct PortExpression {
+    // member channel count of the lower and upper zone, 0 when there's no zone
+    lower_zone: u8,
+    upper_zone: u8,
+    rpn: [(u8, u8); 16],
+    // -1 to 1
+    bend: [f32; 16],
+    bend_range: [f32; 16],
+    pressure: [f32; 16],
+    timbre: [f32; 16],
+    // oldest first
+    notes: Vec<HeldNote>,
+}
+
+impl PortExpression {
+    fn new(lower_zone: u8) -> Self {
+        let mut port = Self {
+            lower_zone: 0,
+            upper_zone: 0,
+            rpn: [(127, 127); 16],
+            bend: [0f32; 16],
+            bend_range: [DEFAULT_BEND_RANGE; 16],
+            pressure: [0f32; 16],
+            timbre: [0.5f32; 16],
+            notes: Vec::new(),
+        };
+        port.set_zone(LOWER_ZONE_MASTER, lower_zone);
+        port
+    }
+
+    // the master channel of the zone the channel is a member of
+    fn zone_master(&self, channel: u8) -> Option<u8> {
+        if channel > LOWER_ZONE_MASTER && channel <= self.lower_zone {
+            Some(LOWER_ZONE_MASTER)
+        } else if channel < UPPER_ZONE_MASTER && channel >= UPPER_ZONE_MASTER - self.upper_zone {
+            Some(UPPER_ZONE_MASTER)
+        } else {
+            None
+        }
+    }
+
+    // a zone growing into the other one shrinks it
+    fn set_zone(&mut self, master: u8, members: u8) {
+        let members = members.min(15);
+        if master == LOWER_ZONE_MASTER {
+            self.lower_zone = members;
+            self.upper_zone = self.upper_zone.min(14 - members.min(14));
+        } else {
+            self.upper_zone = members;
+            self.lower_zone = self.lower_zone.min(14 - members.min(14));
+        }
+        for channel in 0..16u8 {
+            self.bend_range[channel as usize] = match self.zone_master(channel) {
+                Some(_) => MEMBER_BEND_RANGE,
+                None => DEFAULT_BEND_RANGE,
+            };
+        }
+    }
+
+    fn data_entry(&mut self, channel: u8, value: u8) {
+        match self.rpn[channel as usize] {
+            RPN_PITCH_BEND_RANGE => self.bend_range[channel as usize] = value as f32,
+            RPN_MPE_CONFIGURATION
+                if channel == LOWER_ZONE_MASTER || channel == UPPER_ZONE_MASTER =>
+            {
+                self.set_zone(channel, value)
+            }
+            _ => {}
+        }
+    }
+
+    fn apply(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } if velocity > 0 => {
+                self.release(channel, note);
+                self.notes.push(HeldNote {
+                    channel,
+                    note,
+                    poly_pressure: None,
+                });
+            }
+            MidiMessage::NoteOn { channel, note, .. }
+            | MidiMessage::NoteOff { channel, note, .. } => self.release(channel, note),
+            MidiMessage::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => {
+                for held in self.notes.iter_mut() {
+                    if held.channel == channel && held.note == note {
+                        held.poly_pressure = Some(pressure as f32 / 127f32);
+                    }
+                }
+            }
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                self.pressure[channel as usize] = pressure as f32 / 127f32;
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                self.bend[channel as usize] = (value as f32 - 8192f32) / 8192f32;
+            }
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => match controller {
+                CC_TIMBRE => self.timbre[channel as usize] = value as f32 / 127f32,
+                CC_RPN_MSB => self.rpn[channel as usize].0 = value,
+                CC_RPN_LSB => self.rpn[channel as usize].1 = value,
+                CC_DATA_ENTRY => self.data_entry(channel, value),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn release(&mut self, channel: u8, note: u8) {
+        self.notes
+            .retain(|held| held.channel != channel || held.note != note);
+    }
+
+    fn expression(&self, held: &HeldNote) -> NoteExpression {
+        let channel = held.channel as usize;
+        let master_bend = match self.zone_master(held.channel) {
+            Some(master) => self.bend[master as usize] * self.bend_range[master as usize],
+            None => 0f32,
+        };
+        NoteExpression {
+            pressure: held.poly_pressure.unwrap_or(self.pressure[channel]),
+            pitch_bend: self.bend[channel] * self.bend_range[channel] + master_bend,
+            timbre: self.timbre[channel],
+        }
+    }
+}
+
+// per note pitch bend, pressure and timbre of every held note, from polyphonic
+// aftertouch, channel pressure and mpe controllers with a note on each channel
+pub struct ExpressionTracker {
+    ports: HashMap<u8, PortExpression>,
+    // lower zone member channels assumed before the controller announces its zones
+    default_lower_zone: u8,
+}
+
+impl ExpressionTracker {
+    pub fn new() -> Self {
+        Self {
+            ports: HashMap::new(),
+            default_lower_zone: 0,
+        }
+    }
+
+    // for mpe controllers that don't send the mpe configuration message
+    pub fn set_default_lower_zone(&mut self, members: u8) {
+        self.default_lower_zone = members;
+        for port in self.ports.values_mut() {
+            port.set_zone(LOWER_ZONE_MASTER, members);
+        }
+    }
+
+    pub fn apply(&mut self, event: &InputEvent) {
+        let default_lower_zone = self.default_lower_zone;
+        self.ports
+            .entry(event.port)
+            .or_insert_with(|| PortExpression::new(default_lower_zone))
+            .apply(&event.message);
+    }
+
+    // mpe spreads notes over the member channels of a zone, they're all the
+    // zone's master channel to the bindings so a note is the same control
+    // whichever channel it lands on
+    pub fn zone_control(&self, id: ControlId) -> ControlId {
+        if id.kind != ControlKind::Note {
+            return id;
+        }
+        let master = self
+            .ports
+            .get(&id.port)
+            .and_then(|port| port.zone_master(id.channel));
+        match master {
+            Some(channel) => ControlId { channel, ..id },
+            None => id,
+        }
+    }
+
+    // the latest held note that is the note control, None when it isn't held
+    pub fn note(&self, id: ControlId) -> Option<NoteExpression> {
+        if id.kind != ControlKind::Note {
+            return None;
+        }
+        let port = self.ports.get(&id.port)?;
+        port.notes
+            .iter()
+            .rev()
+            .find(|held| {
+                held.note as u16 == id.number
+                    && self.zone_control(ControlId::new(
+                        id.port,
+                        held.channel,
+                        ControlKind::Note,
+                        id.number,
+                    )) == id
+            })
+            .map(|held| port.expression(held))
+    }
+
+    // held notes are let go, zones and bend ranges are kept
+    pub fn clear(&mut self) {
+        for port in self.ports.values_mut() {
+            port.notes.clear();
+        }
+    }
+
+    // the device on the port is gone, what replaces it may not be mpe
+    pub fn clear_port(&mut self, port: u8) {
+        self.ports.remove(&port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(tracker: &mut ExpressionTracker, bytes: &[u8]) {
+        for message in MidiParser::new().parse(bytes) {
+            tracker.apply(&InputEvent {
+                timestamp: 0,
+                port: 0,
+                message,
+                received: None,
+            });
+        }
+    }
+
+    #[test]
+    fn mpe_notes_keep_their_own_expression() {
+        let mut tracker = ExpressionTracker::new();
+        // lower zone with 3 members, then a 12 semitone bend range on the master channel
+        apply(&mut tracker, &[0xB0, 101, 0, 100, 6, 6, 3]);
+        apply(&mut tracker, &[0xB0, 101, 0, 100, 0, 6, 12]);
+        // pressure, bend and timbre are sent before the note on
+        apply(
+            &mut tracker,
+            &[0xD1, 100, 0xE1, 0x00, 0x50, 0xB1, 74, 127, 0x91, 60, 127],
+        );
+        apply(&mut tracker, &[0xD2, 20, 0x92, 60, 64]);
+        // the master bend moves both
+        apply(&mut tracker, &[0xE0, 0x00, 0x60]);
+
+        let latest = tracker.note(ControlId::note(0, 60)).unwrap();
+        // the second note is the latest, half up on the master is 6 of its 12 semitones
+        assert!((latest.pressure - 20f32 / 127f32).abs() < 0.001f32);
+        assert!((latest.pitch_bend - 6f32).abs() < 0.001f32);
+        assert_eq!(latest.timbre, 0.5f32);
+
+        // letting go of the second note leaves the first
+        apply(&mut tracker, &[0x82, 60, 0]);
+        let first = tracker.note(ControlId::note(0, 60)).unwrap();
+        assert!((first.pressure - 100f32 / 127f32).abs() < 0.001f32);
+        // a quarter up on the member's 48 semitones plus half up on the master's 12
+        assert!((first.pitch_bend - (12f32 + 6f32)).abs() < 0.001f32);
+        assert_eq!(first.timbre, 1f32);
+
+        // channel 2 is a member, so the note is the master channel's control
+        assert_eq!(
+            tracker.zone_control(ControlId::note(2, 60)),
+            ControlId::note(0, 60)
+        );
+        // channel 4 is outside the zone
+        assert_eq!(
+            tracker.zone_control(ControlId::note(4, 60)),
+            ControlId::note(4, 60)
+        );
+    }
+
+    #[test]
+    fn poly_pressure_is_per_note() {
+        let mut tracker = ExpressionTracker::new();
+        apply(
+            &mut tracker,
+            &[0x90, 60, 100, 62, 100, 0xA0, 62, 127, 0xD0, 10],
+        );
+        let pressure = |note| tracker.note(ControlId::note(0, note)).unwrap().pressure;
+        assert!((pressure(60) - 10f32 / 127f32).abs() < 0.001f32);
+        assert_eq!(pressure(62), 1f32);
+        assert_eq!(tracker.note(ControlId::note(1, 60)), None);
+    }
+}
@@ -1,6 +1,8 @@
 pub struct Gun {
     is_loaded: bool,
     latch_state: LatchState,
+    // radians above level, negative aims down
+    aim: f32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -19,6 +21,7 @@ impl Gun {
         Self {
             is_loaded: false,
             latch_state: LatchState::Closed,
+            aim: 0f32,
         }
     }
 
@@ -26,6 +29,22 @@ impl Gun {
         self.is_loaded && self.latch_state == LatchState::Closed
     }
 
+    pub fn is_loaded(&self) -> bool {
+        self.is_loaded
+    }
+
+    pub fn aim(&self) -> f32 {
+        self.aim
+    }
+
+    pub fn set_aim(&mut self, aim: f32) {
+        self.aim = aim;
+    }
+
+    pub fn latch_state(&self) -> LatchState {
+        self.latch_state
+    }
+
     pub fn set_latch_state(&mut self, state: LatchState) {
         if self.latch_state == LatchState::Open && state == LatchState::Closed {
             self.is_loaded = true;
@@ -1,23 +1,114 @@
-use midir;
+use crate::bindings::*;
+use crate::expression::*;
+use crate::midi_message::*;
+use crate::midi_source::*;
+use crate::pickup::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::string::*;
-use std::sync::{Arc, Mutex};
+use std::fmt;
 
-pub struct Input {
-    input_port: midir::MidiInputPort,
-    device_name: String,
-    // optional because it needs to be consumed and sent to the connection thread
-    midi_input: Option<midir::MidiInput>,
-    connection: Option<midir::MidiInputConnection<()>>,
-
-    raw_inputs: Arc<Mutex<HashMap<u8, InputDataRaw>>>,
-    previous_raw_inputs: Arc<Mutex<HashMap<u8, InputDataRaw>>>,
+#[derive(Hash, Eq, Clone, Debug, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ControlKind {
+    Note,
+    ControlChange,
+    PolyPressure,
+    ChannelPressure,
+    PitchBend,
+    // number is the 14 bit parameter number
+    Nrpn,
+    Rpn,
 }
 
-#[derive(Eq, Clone, Debug, Copy, PartialEq)]
-pub struct InputDataRaw {
-    timestamp: u64,
-    value: u8,
+// identifies one physical control, so the same number on another channel,
+// another port or another message kind is a different control
+// channel is 0 based, MIDI channel 10 is channel 9
+#[derive(Hash, Eq, Clone, Debug, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ControlId {
+    #[serde(default)]
+    pub port: u8,
+    pub channel: u8,
+    pub kind: ControlKind,
+    pub number: u16,
+}
+
+impl ControlId {
+    pub const fn new(port: u8, channel: u8, kind: ControlKind, number: u16) -> Self {
+        Self {
+            port,
+            channel,
+            kind,
+            number,
+        }
+    }
+
+    pub const fn note(channel: u8, note: u8) -> Self {
+        Self::new(0, channel, ControlKind::Note, note as u16)
+    }
+
+    pub const fn cc(channel: u8, controller: u8) -> Self {
+        Self::new(0, channel, ControlKind::ControlChange, controller as u16)
+    }
+
+    // returns the control a message belongs to along with its 7 bit value
+    pub fn from_message(port: u8, message: &MidiMessage) -> Option<(Self, u8)> {
+        let channel = message.channel()?;
+        match *message {
+            MidiMessage::NoteOn { note, velocity, .. } => Some((
+                Self::new(port, channel, ControlKind::Note, note as u16),
+                velocity,
+            )),
+            MidiMessage::NoteOff { note, .. } => {
+                Some((Self::new(port, channel, ControlKind::Note, note as u16), 0))
+            }
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => Some((
+                Self::new(port, channel, ControlKind::ControlChange, controller as u16),
+                value,
+            )),
+            MidiMessage::PolyPressure { note, pressure, .. } => Some((
+                Self::new(port, channel, ControlKind::PolyPressure, note as u16),
+                pressure,
+            )),
+            MidiMessage::ChannelPressure { pressure, .. } => Some((
+                Self::new(port, channel, ControlKind::ChannelPressure, 0),
+                pressure,
+            )),
+            MidiMessage::PitchBend { value, .. } => Some((
+                Self::new(port, channel, ControlKind::PitchBend, 0),
+                (value >> 7) as u8,
+            )),
+            MidiMessage::ProgramChange { .. }
+            | MidiMessage::TimingClock
+            | MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::Stop
+            | MidiMessage::SongPosition { .. } => None,
+        }
+    }
+}
+
+// channel is shown 1 based like on the hardware
+impl fmt::Display for ControlId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            ControlKind::Note => "note",
+            ControlKind::ControlChange => "cc",
+            ControlKind::PolyPressure => "poly pressure",
+            ControlKind::ChannelPressure => "pressure",
+            ControlKind::PitchBend => "pitch bend",
+            ControlKind::Nrpn => "nrpn",
+            ControlKind::Rpn => "rpn",
+        };
+        write!(
+            f,
+            "port {} ch {} {} {}",
+            self.port,
+            self.channel + 1,
+            kind,
+            self.number
+        )
+    }
 }
 
 #[derive(Eq, Clone, Debug, Copy, PartialEq)]
@@ -26,136 +117,226 @@ pub enum SliderLimitCheck {
     Lower,
 }
 
-impl Input {
-    pub fn new() -> Self {
-        let midi_input = midir::MidiInput::new("Input device").unwrap();
-        // grab first device
-        let input_port = match midi_input.ports().into_iter().next() {
-            Some(port) => port,
-            None => panic!("NO MIDI DEVICE FOUND!"),
-        };
+// anything gameplay can read buttons and faders from
+pub trait InputSource {
+    fn is_button_held(&self, id: ControlId) -> bool;
+    fn is_button_pressed(&self, id: ControlId) -> bool;
+    fn is_button_released(&self, id: ControlId) -> bool;
+    // how hard the button was last pressed, 0 to 1
+    fn button_velocity(&self, _id: ControlId) -> Option<f32> {
+        None
+    }
+    // None when the source has never seen this control
+    fn fraction(&self, id: ControlId) -> Option<f32>;
+    // let's say we want to trigger every time slider goes from under 0.5 to over 0.5
+    fn fraction_reached_limit(&self, id: ControlId, fraction: f32, limit: SliderLimitCheck)
+        -> bool;
+    // a fader used as a switch reached a threshold of its trigger
+    fn trigger_reached(&self, id: ControlId, trigger: &Trigger, limit: SliderLimitCheck) -> bool {
+        self.fraction_reached_limit(id, trigger.threshold(limit), limit)
+    }
+    // pressure, bend and timbre of the held note, None for other controls
+    fn note_expression(&self, _id: ControlId) -> Option<NoteExpression> {
+        None
+    }
+    // called at the start of every frame
+    fn update(&mut self, _dt: f32) {}
+    // for sources that map their own controls to game actions
+    fn set_bindings(&mut self, _bindings: &Bindings) {}
+    // called at the end of every frame
+    fn flush(&mut self) {}
+}
 
-        let device_name = midi_input
-            .port_name(&input_port)
-            .expect("can't get name of port");
+// merges the midi device with any other sources, a button counts if any source has it down
+// and fractions come from the first source that knows the control, midi first
+pub struct Input {
+    pub midi: MidiSource,
+    sources: Vec<Box<dyn InputSource>>,
+    bindings: Bindings,
+    // actions bound with pickup that the faders may not have caught yet
+    pickups: HashMap<GameAction, Pickup>,
+}
 
+impl Input {
+    pub fn new(midi: MidiSource) -> Self {
         Self {
-            midi_input: Some(midi_input),
-            input_port,
-            device_name,
-            connection: None,
-            raw_inputs: Arc::new(Mutex::new(HashMap::with_capacity(16))),
-            previous_raw_inputs: Arc::new(Mutex::new(HashMap::with_capacity(16))),
+            midi,
+            sources: Vec::new(),
+            bindings: Bindings::default(),
+            pickups: HashMap::new(),
         }
     }
 
-    pub fn is_button_held(&self, id: u8) -> bool {
-        let mut raw_inputs = self.raw_inputs.lock().unwrap();
-        let mut previous_raw_inputs = self.previous_raw_inputs.lock().unwrap();
-        if let Some(raw_input) = raw_inputs.get_mut(&id) {
-            if let Some(previous_raw_input) = previous_raw_inputs.get_mut(&id) {
-                raw_input.value == 127 && previous_raw_input.value == 127
-            } else {
-                raw_input.value == 127
-            }
-        } else {
-            if let Some(previous_raw_input) = previous_raw_inputs.get_mut(&id) {
-                previous_raw_input.value == 127
-            } else {
-                false
-            }
+    pub fn add_source(&mut self, mut source: Box<dyn InputSource>) {
+        source.set_bindings(&self.bindings);
+        self.sources.push(source);
+    }
+
+    pub fn bindings(&self) -> &Bindings {
+        &self.bindings
+    }
+
+    pub fn set_bindings(&mut self, bindings: Bindings) {
+        self.midi.set_bindings(&bindings);
+        for source in self.sources.iter_mut() {
+            source.set_bindings(&bindings);
+        }
+        self.bindings = bindings;
+        // faders may have moved while the old bindings were used, so pickup starts over
+        // from whatever the game was reading
+        let pickups = self
+            .bindings
+            .actions
+            .iter()
+            .filter(|(_, binding)| binding.uses_pickup())
+            .map(|(action, binding)| {
+                let value = match self.pickups.get(action) {
+                    Some(pickup) => pickup.value(),
+                    None => self.get_fraction(binding.control),
+                };
+                (*action, Pickup::new(value))
+            })
+            .collect();
+        self.pickups = pickups;
+    }
+
+    // sets the value the game has for an action, call when the game starts or a scene
+    // changes so faders bound with pickup take over from there
+    pub fn hold_action(&mut self, action: GameAction, value: f32) {
+        if self.pickups.contains_key(&action) {
+            self.pickups.insert(action, Pickup::new(value));
         }
     }
 
-    pub fn is_button_pressed(&self, id: u8) -> bool {
-        let mut raw_inputs = self.raw_inputs.lock().unwrap();
-        if let Some(raw_input) = raw_inputs.get_mut(&id) {
-            raw_input.value == 127
-        } else {
-            false
+    // actions whose fader hasn't caught the game value yet,
+    // with the held value and where the fader is
+    pub fn pending_pickups(&self) -> impl Iterator<Item = (GameAction, f32, Option<f32>)> + '_ {
+        self.pickups
+            .iter()
+            .filter(|(_, pickup)| !pickup.is_caught())
+            .map(|(action, pickup)| (*action, pickup.value(), pickup.fader()))
+    }
+
+    // unbound actions are never held and always at 0
+    pub fn action_held(&self, action: GameAction) -> bool {
+        match self.bindings.control(action) {
+            Some(id) => self.is_button_held(id),
+            None => false,
         }
     }
 
-    pub fn is_button_released(&self, id: u8) -> bool {
-        !self.is_button_pressed(id)
+    pub fn action_pressed(&self, action: GameAction) -> bool {
+        match self.bindings.control(action) {
+            Some(id) => self.is_button_pressed(id),
+            None => false,
+        }
     }
 
-    fn convert_to_fraction(v: u8) -> f32 {
-        v as f32 / 127f32
+    pub fn action_released(&self, action: GameAction) -> bool {
+        match self.bindings.control(action) {
+            Some(id) => self.is_button_released(id),
+            None => false,
+        }
     }
 
-    pub fn get_fraction(&self, id: u8) -> f32 {
-        let mut raw_inputs = self.raw_inputs.lock().unwrap();
-        let mut previous_raw_inputs = self.previous_raw_inputs.lock().unwrap();
-        if let Some(raw_input) = raw_inputs.get_mut(&id) {
-            Self::convert_to_fraction(raw_input.value)
-        } else if let Some(previous_raw_input) = previous_raw_inputs.get_mut(&id) {
-            Self::convert_to_fraction(previous_raw_input.value)
-        } else {
-            0f32
+    pub fn action_velocity(&self, action: GameAction) -> f32 {
+        match self.bindings.control(action) {
+            Some(id) => self.button_velocity(id),
+            None => 0f32,
         }
     }
 
-    // let's say we want to trigger every time slider goes from under 0.5 to over 0.5
-    pub fn fraction_reached_limit(&self, id: u8, fraction: f32, limit: SliderLimitCheck) -> bool {
-        let mut raw_inputs = self.raw_inputs.lock().unwrap();
-        let mut previous_raw_inputs = self.previous_raw_inputs.lock().unwrap();
-        if let Some(raw_input) = raw_inputs.get_mut(&id) {
-            if let Some(previous_raw_input) = previous_raw_inputs.get_mut(&id) {
-                return match limit {
-                    SliderLimitCheck::Lower => {
-                        Self::convert_to_fraction(raw_input.value) <= fraction
-                            && Self::convert_to_fraction(previous_raw_input.value) > fraction
-                    }
-                    SliderLimitCheck::Higher => {
-                        Self::convert_to_fraction(raw_input.value) >= fraction
-                            && Self::convert_to_fraction(previous_raw_input.value) < fraction
-                    }
-                };
+    pub fn action_fraction(&self, action: GameAction) -> f32 {
+        if let Some(pickup) = self.pickups.get(&action) {
+            return pickup.value();
+        }
+        match self.bindings.control(action) {
+            Some(id) => self.get_fraction(id),
+            None => 0f32,
+        }
+    }
+
+    // how the note bound to the action is played while it's held,
+    // pressure and bend from aftertouch or an mpe controller
+    pub fn action_expression(&self, action: GameAction) -> Option<NoteExpression> {
+        let id = self.bindings.control(action)?;
+        self.all_sources()
+            .find_map(|source| source.note_expression(id))
+    }
+
+    // the thresholds come from the trigger of the binding
+    pub fn action_triggered(&self, action: GameAction, limit: SliderLimitCheck) -> bool {
+        match self.bindings.actions.get(&action) {
+            Some(binding) => self
+                .all_sources()
+                .any(|source| source.trigger_reached(binding.control, &binding.trigger, limit)),
+            None => false,
+        }
+    }
+
+    fn all_sources(&self) -> impl Iterator<Item = &dyn InputSource> {
+        std::iter::once(&self.midi as &dyn InputSource)
+            .chain(self.sources.iter().map(|source| source.as_ref()))
+    }
+
+    pub fn is_button_held(&self, id: ControlId) -> bool {
+        self.all_sources().any(|source| source.is_button_held(id))
+    }
+
+    pub fn is_button_pressed(&self, id: ControlId) -> bool {
+        self.all_sources()
+            .any(|source| source.is_button_pressed(id))
+    }
+
+    pub fn is_button_released(&self, id: ControlId) -> bool {
+        self.all_sources()
+            .any(|source| source.is_button_released(id))
+    }
+
+    // sources without velocity count as hit at full strength
+    pub fn button_velocity(&self, id: ControlId) -> f32 {
+        self.all_sources()
+            .filter(|source| source.is_button_held(id) || source.is_button_pressed(id))
+            .map(|source| source.button_velocity(id).unwrap_or(1f32))
+            .fold(0f32, f32::max)
+    }
+
+    pub fn get_fraction(&self, id: ControlId) -> f32 {
+        self.find_fraction(id).unwrap_or(0f32)
+    }
+
+    fn find_fraction(&self, id: ControlId) -> Option<f32> {
+        self.all_sources().find_map(|source| source.fraction(id))
+    }
+
+    pub fn fraction_reached_limit(
+        &self,
+        id: ControlId,
+        fraction: f32,
+        limit: SliderLimitCheck,
+    ) -> bool {
+        self.all_sources()
+            .any(|source| source.fraction_reached_limit(id, fraction, limit))
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.midi.update(dt);
+        for source in self.sources.iter_mut() {
+            source.update(dt);
+        }
+        let mut pickups = std::mem::take(&mut self.pickups);
+        for (action, pickup) in pickups.iter_mut() {
+            if let Some(id) = self.bindings.control(*action) {
+                pickup.update(self.find_fraction(id));
             }
         }
-        false
+        self.pickups = pickups;
     }
 
-    // clear all inputs, update previous values
     pub fn flush(&mut self) {
-        let mut prev_raw_inputs = self.previous_raw_inputs.lock().unwrap();
-        let mut raw_inputs = self.raw_inputs.lock().unwrap();
-        // store latests values as previous
-        for (id, raw_input) in raw_inputs.iter_mut() {
-            if let Some(mut prev_raw) = prev_raw_inputs.get_mut(&id) {
-                *prev_raw = *raw_input;
-            } else {
-                prev_raw_inputs.insert(*id, *raw_input);
-            }
+        self.midi.flush();
+        for source in self.sources.iter_mut() {
+            source.flush();
         }
-        raw_inputs.clear();
-    }
-
-    pub fn connect(&mut self) {
-        let raw_inputs = self.raw_inputs.clone();
-        self.connection = Some(
-            self.midi_input
-                .take() // consume midi_input because it will be sent to thread
-                .unwrap()
-                .connect(
-                    &self.input_port,
-                    self.device_name.as_str(),
-                    move |stamp, message, _| {
-                        //println!("{}: {:?} (len = {})", stamp, message, message.len());
-                        let mut rw = raw_inputs.lock().unwrap();
-                        let identifier = message[1];
-                        rw.insert(
-                            identifier,
-                            InputDataRaw {
-                                timestamp: stamp,
-                                value: message[2],
-                            },
-                        );
-                    },
-                    (),
-                )
-                .expect("can't connect to midi device"),
-        );
     }
 }
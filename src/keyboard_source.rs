@@ -0,0 +1,148 @@
+use crate::bindings::*;
+use crate::input::*;
+use macroquad::prelude::*;
+
+// how fast a fader moves while its key is held, full range per second
+const FADER_SPEED: f32 = 1f32;
+const WHEEL_STEP: f32 = 0.05f32;
+
+// a fader driven by two keys, one moves it down and one moves it up
+struct KeyFader {
+    action: GameAction,
+    down: KeyCode,
+    up: KeyCode,
+    value: f32,
+    previous_value: f32,
+}
+
+// lets the game be played without a controller by pretending keys are
+// the midi controls bound to each action
+pub struct KeyboardSource {
+    buttons: Vec<(KeyCode, GameAction)>,
+    faders: Vec<KeyFader>,
+    // the mouse wheel drives the fader of this action
+    wheel_fader: Option<GameAction>,
+    bindings: Bindings,
+}
+
+impl KeyboardSource {
+    pub fn new() -> Self {
+        Self {
+            buttons: Vec::new(),
+            faders: Vec::new(),
+            wheel_fader: None,
+            bindings: Bindings::default(),
+        }
+    }
+
+    // keys on the left hand, camera around the shoot key on the right, clear of the
+    // arrows, enter, esc, c and backspace the bindings screen uses
+    pub fn with_default_layout() -> Self {
+        let mut source = Self::new();
+        use GameAction::*;
+        source.add_button(KeyCode::A, MoveLeft);
+        source.add_button(KeyCode::D, MoveRight);
+        source.add_button(KeyCode::Space, Jump);
+        source.add_button(KeyCode::J, Shoot);
+        source.add_fader(JumpStrength, KeyCode::Z, KeyCode::X, 0.5f32);
+        source.add_fader(Latch, KeyCode::Q, KeyCode::E, 0f32);
+        source.add_fader(Crouch, KeyCode::S, KeyCode::W, 1f32);
+        source.add_fader(WaterStrength, KeyCode::F, KeyCode::G, 0.5f32);
+        source.add_fader(WaterSpeed, KeyCode::B, KeyCode::N, 0.5f32);
+        source.add_fader(CameraX, KeyCode::U, KeyCode::O, 0.5f32);
+        source.add_fader(CameraY, KeyCode::K, KeyCode::I, 0.5f32);
+        source.set_wheel_fader(JumpStrength);
+        source
+    }
+
+    pub fn add_button(&mut self, key: KeyCode, action: GameAction) {
+        self.buttons.push((key, action));
+    }
+
+    pub fn add_fader(&mut self, action: GameAction, down: KeyCode, up: KeyCode, value: f32) {
+        self.faders.push(KeyFader {
+            action,
+            down,
+            up,
+            value,
+            previous_value: value,
+        });
+    }
+
+    // the wheel moves an already added fader
+    pub fn set_wheel_fader(&mut self, action: GameAction) {
+        self.wheel_fader = Some(action);
+    }
+
+    fn keys(&self, id: ControlId) -> impl Iterator<Item = KeyCode> + '_ {
+        self.buttons
+            .iter()
+            .filter(move |(_, action)| self.bindings.control(*action) == Some(id))
+            .map(|(key, _)| *key)
+    }
+
+    fn fader(&self, id: ControlId) -> Option<&KeyFader> {
+        self.faders
+            .iter()
+            .find(|fader| self.bindings.control(fader.action) == Some(id))
+    }
+}
+
+impl InputSource for KeyboardSource {
+    fn is_button_held(&self, id: ControlId) -> bool {
+        self.keys(id).any(is_key_down)
+    }
+
+    fn is_button_pressed(&self, id: ControlId) -> bool {
+        self.keys(id).any(is_key_pressed)
+    }
+
+    fn is_button_released(&self, id: ControlId) -> bool {
+        self.keys(id).any(is_key_released)
+    }
+
+    fn fraction(&self, id: ControlId) -> Option<f32> {
+        self.fader(id).map(|fader| fader.value)
+    }
+
+    fn fraction_reached_limit(
+        &self,
+        id: ControlId,
+        fraction: f32,
+        limit: SliderLimitCheck,
+    ) -> bool {
+        match self.fader(id) {
+            Some(fader) => match limit {
+                SliderLimitCheck::Lower => {
+                    fader.value <= fraction && fader.previous_value > fraction
+                }
+                SliderLimitCheck::Higher => {
+                    fader.value >= fraction && fader.previous_value < fraction
+                }
+            },
+            None => false,
+        }
+    }
+
+    fn update(&mut self, dt: f32) {
+        let (_, wheel_y) = mouse_wheel();
+        for fader in self.faders.iter_mut() {
+            fader.previous_value = fader.value;
+            if is_key_down(fader.down) {
+                fader.value -= FADER_SPEED * dt;
+            }
+            if is_key_down(fader.up) {
+                fader.value += FADER_SPEED * dt;
+            }
+            // signum of an idle wheel is 1, not 0
+            if Some(fader.action) == self.wheel_fader && wheel_y != 0f32 {
+                fader.value += wheel_y.signum() * WHEEL_STEP;
+            }
+            fader.value = fader.value.max(0f32).min(1f32);
+        }
+    }
+
+    fn set_bindings(&mut self, bindings: &Bindings) {
+        self.bindings = bindings.clone();
+    }
+}
@@ -0,0 +1,315 @@
+use crate::event_queue::*;
+use crate::input::*;
+use crate::midi_message::*;
+use crate::midi_source::*;
+use std::collections::HashMap;
+use std::time::Instant;
+
+// 1 ms per bucket, the last one holds everything slower
+const HISTOGRAM_BUCKETS: usize = 20;
+// how often the report is printed
+const REPORT_INTERVAL: f32 = 5f32;
+// loopback notes are sent on the last channel with the highest note,
+// the velocity numbers them so each one can be matched to when it was sent
+pub const LOOPBACK_CHANNEL: u8 = 15;
+pub const LOOPBACK_NOTE: u8 = 127;
+const LOOPBACK_INTERVAL: f32 = 0.1f32;
+
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    buckets: [u32; HISTOGRAM_BUCKETS],
+    count: u32,
+    total_ms: f32,
+    max_ms: f32,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            count: 0,
+            total_ms: 0f32,
+            max_ms: 0f32,
+        }
+    }
+
+    pub fn add(&mut self, ms: f32) {
+        let bucket = (ms.max(0f32) as usize).min(HISTOGRAM_BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    pub fn mean(&self) -> f32 {
+        match self.count {
+            0 => 0f32,
+            count => self.total_ms / count as f32,
+        }
+    }
+
+    // upper edge of the bucket the percentile falls in, 0.95 for the 95th
+    pub fn percentile(&self, percentile: f32) -> f32 {
+        let wanted = (self.count as f32 * percentile).ceil() as u32;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= wanted && seen > 0 {
+                return (bucket + 1) as f32;
+            }
+        }
+        0f32
+    }
+
+    // one character per bucket, taller is more events
+    pub fn bars(&self) -> String {
+        const BARS: [char; 8] = ['.', ':', '-', '=', '+', '*', '#', '@'];
+        let highest = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        self.buckets
+            .iter()
+            .map(|count| match count {
+                0 => ' ',
+                count => BARS[(*count as usize * (BARS.len() - 1)) / highest as usize],
+            })
+            .collect()
+    }
+
+    pub fn summary(&self, name: &str) -> String {
+        format!(
+            "{}: {} events, mean {:.1} ms, p95 {:.0} ms, max {:.1} ms |{}|",
+            name,
+            self.count,
+            self.mean(),
+            self.percentile(0.95f32),
+            self.max_ms,
+            self.bars()
+        )
+    }
+}
+
+// sends numbered notes to the game's own virtual port, so the whole trip through
+// the midi backend, the queue and the frame loop can be measured
+struct Loopback {
+    connection: midir::MidiOutputConnection,
+    timer: f32,
+    next_number: u8,
+    sent: HashMap<u8, Instant>,
+}
+
+impl Loopback {
+    fn connect() -> Result<Self, String> {
+        let midi_output =
+            midir::MidiOutput::new("Latency loopback").map_err(|err| err.to_string())?;
+        let port = midi_output
+            .ports()
+            .into_iter()
+            .find(|port| {
+                midi_output
+                    .port_name(port)
+                    .is_ok_and(|name| name.contains(VIRTUAL_INPUT_PORT))
+            })
+            .ok_or_else(|| {
+                format!(
+                    "no {} port, is the virtual midi port on?",
+                    VIRTUAL_INPUT_PORT
+                )
+            })?;
+        let connection = midi_output
+            .connect(&port, "latency loopback")
+            .map_err(|err| err.to_string())?;
+        Ok(Self {
+            connection,
+            timer: 0f32,
+            next_number: 1,
+            sent: HashMap::new(),
+        })
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.timer -= dt;
+        if self.timer > 0f32 {
+            return;
+        }
+        self.timer = LOOPBACK_INTERVAL;
+        let message = MidiMessage::NoteOn {
+            channel: LOOPBACK_CHANNEL,
+            note: LOOPBACK_NOTE,
+            velocity: self.next_number,
+        };
+        if self.connection.send(&message.to_bytes()).is_ok() {
+            self.sent.insert(self.next_number, Instant::now());
+        }
+        // velocity 0 would be a note off
+        self.next_number = self.next_number % 127 + 1;
+    }
+}
+
+// measures how late midi events reach the game, turned on with --latency-diagnostics
+pub struct LatencyMonitor {
+    start: Instant,
+    // from the midi thread receiving an event to the frame that reads it
+    queue_latency: Histogram,
+    // arrival compared to midir's timestamp, relative to the most punctual event seen,
+    // shows how unevenly the backend delivers
+    jitter: Histogram,
+    // by port, every device's timestamps count from when it was connected
+    min_offsets: HashMap<u8, i64>,
+    // from sending a loopback note to the frame that reads it
+    loopback_latency: Histogram,
+    frames: u32,
+    frame_time: f32,
+    busiest_frame: usize,
+    // values of a control that were replaced by another value of it in the same frame
+    coalesced: usize,
+    dropped: usize,
+    last_dropped_total: Option<usize>,
+    report_timer: f32,
+    loopback: Option<Loopback>,
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            queue_latency: Histogram::new(),
+            jitter: Histogram::new(),
+            min_offsets: HashMap::new(),
+            loopback_latency: Histogram::new(),
+            frames: 0,
+            frame_time: 0f32,
+            busiest_frame: 0,
+            coalesced: 0,
+            dropped: 0,
+            last_dropped_total: None,
+            report_timer: REPORT_INTERVAL,
+            loopback: None,
+        }
+    }
+
+    // needs the virtual midi port to be open
+    pub fn start_loopback(&mut self) {
+        match Loopback::connect() {
+            Ok(loopback) => self.loopback = Some(loopback),
+            Err(err) => println!("can't start latency loopback: {}", err),
+        }
+    }
+
+    // call right after the midi source drained the queue
+    pub fn measure_frame(&mut self, dt: f32, events: &[InputEvent], dropped_total: usize) {
+        let now = Instant::now();
+        self.frames += 1;
+        self.frame_time += dt;
+        self.busiest_frame = self.busiest_frame.max(events.len());
+        self.dropped += dropped_total - self.last_dropped_total.unwrap_or(dropped_total);
+        self.last_dropped_total = Some(dropped_total);
+
+        let mut seen = Vec::with_capacity(events.len());
+        for event in events {
+            if let Some(received) = event.received {
+                self.queue_latency
+                    .add(now.duration_since(received).as_secs_f32() * 1000f32);
+                let arrival = received.saturating_duration_since(self.start).as_micros() as i64;
+                let offset = arrival - event.timestamp as i64;
+                let min_offset = self.min_offsets.entry(event.port).or_insert(offset);
+                *min_offset = (*min_offset).min(offset);
+                self.jitter.add((offset - *min_offset) as f32 / 1000f32);
+            }
+            if let MidiMessage::NoteOn {
+                channel: LOOPBACK_CHANNEL,
+                note: LOOPBACK_NOTE,
+                velocity,
+            } = event.message
+            {
+                if let Some(sent) = self
+                    .loopback
+                    .as_mut()
+                    .and_then(|loopback| loopback.sent.remove(&velocity))
+                {
+                    self.loopback_latency
+                        .add(now.duration_since(sent).as_secs_f32() * 1000f32);
+                }
+            }
+            if let Some((id, _)) = ControlId::from_message(event.port, &event.message) {
+                if seen.contains(&id) {
+                    self.coalesced += 1;
+                } else {
+                    seen.push(id);
+                }
+            }
+        }
+    }
+
+    // sends loopback notes and prints the report now and then
+    pub fn update(&mut self, dt: f32) {
+        if let Some(loopback) = &mut self.loopback {
+            loopback.update(dt);
+        }
+        self.report_timer -= dt;
+        if self.report_timer <= 0f32 {
+            self.report_timer = REPORT_INTERVAL;
+            println!("--- midi latency ---");
+            for line in self.summary() {
+                println!("{}", line);
+            }
+        }
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        let mean_frame_ms = match self.frames {
+            0 => 0f32,
+            frames => self.frame_time / frames as f32 * 1000f32,
+        };
+        let mut lines = vec![
+            format!(
+                "frames: {}, mean {:.1} ms, busiest {} events, {} coalesced, {} dropped",
+                self.frames, mean_frame_ms, self.busiest_frame, self.coalesced, self.dropped
+            ),
+            self.queue_latency.summary("to frame"),
+            self.jitter.summary("jitter"),
+        ];
+        if self.loopback.is_some() {
+            lines.push(self.loopback_latency.summary("loopback"));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentile_is_the_bucket_edge() {
+        let mut histogram = Histogram::new();
+        for ms in 0..19 {
+            histogram.add(0.5f32 + ms as f32 * 0.01f32);
+        }
+        histogram.add(42f32);
+        assert_eq!(histogram.percentile(0.5f32), 1f32);
+        assert_eq!(histogram.percentile(1f32), HISTOGRAM_BUCKETS as f32);
+        assert_eq!(histogram.max_ms, 42f32);
+        assert!(histogram.bars().starts_with('@'));
+    }
+
+    #[test]
+    fn jitter_is_measured_per_port() {
+        let mut monitor = LatencyMonitor::new();
+        let start = monitor.start;
+        let event = |port, timestamp, arrival| InputEvent {
+            timestamp,
+            port,
+            message: MidiMessage::ControlChange {
+                channel: 0,
+                controller: 1,
+                value: 0,
+            },
+            received: Some(start + std::time::Duration::from_micros(arrival)),
+        };
+        // the second device was connected long after the first, its timestamps are far behind
+        monitor.measure_frame(0f32, &[event(0, 1_000_000, 1_001_000)], 0);
+        monitor.measure_frame(0f32, &[event(1, 1_000, 1_002_000)], 0);
+        monitor.measure_frame(0f32, &[event(0, 1_002_000, 1_003_000)], 0);
+        monitor.measure_frame(0f32, &[event(1, 2_000, 1_003_000)], 0);
+        assert_eq!(monitor.jitter.mean(), 0f32);
+    }
+}
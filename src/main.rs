@@ -1,7 +1,19 @@
+use crate::bindings::*;
+use crate::bindings_state::*;
+use crate::config::*;
 use crate::constants::*;
+use crate::device_picker::*;
 use crate::gun::*;
 use crate::input::*;
+use crate::keyboard_source::*;
+use crate::latency::*;
+use crate::midi_feedback::*;
+use crate::midi_file::*;
+use crate::midi_file_source::*;
+use crate::midi_monitor::*;
+use crate::midi_source::*;
 use crate::player::*;
+use crate::recording::*;
 use crate::resources::*;
 use crate::water::*;
 use async_trait::async_trait;
@@ -12,11 +24,34 @@ use macroquad_tantan_toolbox::resources::*;
 use macroquad_tantan_toolbox::states::*;
 use std::collections::HashMap;
 
+mod beat_clock;
+mod bindings;
+mod bindings_state;
+mod config;
 mod constants;
+mod control_decoder;
+mod control_state;
+mod device_picker;
+mod event_queue;
+mod expression;
 mod gun;
 mod input;
+mod keyboard_source;
+mod latency;
+mod midi_feedback;
+mod midi_file;
+mod midi_file_source;
+mod midi_message;
+mod midi_monitor;
+mod midi_source;
+mod network_midi;
+mod osc;
+mod pickup;
 mod player;
+mod recording;
 mod resources;
+mod rtp_midi;
+mod sysex;
 mod water;
 
 pub struct GameStateData {
@@ -47,13 +82,19 @@ impl State<TransitionData, SharedData> for GameState {
             .player;
         let player_pos = vec2(GAME_SIZE.x * 0.5f32, GAME_SIZE.y * 0.5f32);
         let player = Player::new(player_pos, player_texture);
-        let water_normal = shared_data
+        // faders bound with pickup take over from the starting values
+        let input = &mut shared_data.input;
+        input.hold_action(GameAction::JumpStrength, player.jump_strength);
+        input.hold_action(GameAction::WaterSpeed, WATER_SPEED / MAX_WATER_SPEED);
+        input.hold_action(
+            GameAction::WaterStrength,
+            WATER_STRENGTH / MAX_WATER_STRENGTH,
+        );
+        let water_normal = &shared_data
             .raw_image_resources_optional
-            .as_mut()
+            .as_ref()
             .unwrap()
-            .water_normal
-            .take()
-            .unwrap();
+            .water_normal;
         self.data_optional = Some(GameStateData {
             player,
             water: MyWater::new(
@@ -72,22 +113,85 @@ impl State<TransitionData, SharedData> for GameState {
     ) -> Option<StateManagerCommand<TransitionData, SharedData>> {
         let shared_data = &mut payload.shared_data;
         let dt = get_frame_time();
+        shared_data.poll_devices(dt);
+        shared_data.input.update(dt);
+        let events = shared_data.input.midi.events_this_frame();
+        shared_data.clock.update(dt, events);
+        if let Some(latency) = &mut shared_data.latency {
+            let midi = &shared_data.input.midi;
+            latency.measure_frame(dt, midi.events_this_frame(), midi.dropped_events());
+            latency.update(dt);
+        }
+        shared_data.monitor.update(&shared_data.input);
         if let Some(game_data) = &mut self.data_optional {
+            let clock = &shared_data.clock;
+            game_data.player.animation_speed = match clock.bpm() {
+                Some(bpm) if clock.is_playing() => (bpm / ANIMATION_BPM).max(0.5f32).min(2f32),
+                _ => 1f32,
+            };
             game_data.player.update(dt);
             game_data.player.process_input(dt, shared_data);
 
-            let water_speed = shared_data.input.get_fraction(4) * 0.5f32;
-            let water_strength = shared_data.input.get_fraction(3) * 0.3f32;
+            let water_speed =
+                shared_data.input.action_fraction(GameAction::WaterSpeed) * MAX_WATER_SPEED;
+            let mut water_strength =
+                shared_data.input.action_fraction(GameAction::WaterStrength) * MAX_WATER_STRENGTH;
+            // the waves swell on the beat and settle until the next one
+            if let Some(phase) = shared_data.clock.beat_phase() {
+                water_strength *= 1f32 + BEAT_WATER_SWELL * (1f32 - phase).powi(2);
+            }
             game_data.water.water.strength = water_strength;
             game_data.water.water.speed = water_speed;
             game_data.water.water.update(dt);
         }
         let mul = 500f32;
-        let x = shared_data.input.get_fraction(6) * mul;
-        let y = 50f32 + shared_data.input.get_fraction(7) * 130.;
+        if let Some(game_data) = &self.data_optional {
+            let feedback = &mut shared_data.feedback;
+            let gun = &game_data.player.gun;
+            feedback.set_on(
+                FeedbackSource::LatchOpen,
+                gun.latch_state() == LatchState::Open,
+            );
+            feedback.set_on(FeedbackSource::GunLoaded, gun.is_loaded());
+            feedback.set_on(FeedbackSource::Crouching, game_data.player.is_crouching);
+            for action in GameAction::ALL.iter().filter(|action| !action.is_button()) {
+                let fraction = shared_data.input.action_fraction(*action);
+                feedback.set(FeedbackSource::Action(*action), fraction);
+            }
+            feedback.send();
+        }
+        let x = shared_data.input.action_fraction(GameAction::CameraX) * mul;
+        let y = 50f32 + shared_data.input.action_fraction(GameAction::CameraY) * 130.;
         payload.camera.target = vec2(x, y);
 
         shared_data.input.flush();
+
+        if is_key_pressed(KeyCode::F3) {
+            shared_data.monitor.toggle();
+        }
+        let midi = &mut shared_data.input.midi;
+        if is_key_pressed(KeyCode::F5) {
+            if midi.is_recording() {
+                midi.stop_recording();
+            } else {
+                midi.start_recording(RECORDING_PATH);
+            }
+        }
+        if is_key_pressed(KeyCode::F6) {
+            if midi.is_replaying() {
+                midi.stop_replay();
+            } else {
+                midi.start_replay(RECORDING_PATH);
+            }
+        }
+
+        if is_key_pressed(KeyCode::F2) {
+            return Some(StateManagerCommand::ChangeStateEx(
+                Box::new(BindingsState::new()),
+                TransitionTime(0.3),
+                TransitionData::Slide,
+            ));
+        }
         None
     }
     fn on_draw(&mut self, mut payload: StateManagerPayload<SharedData>) {
@@ -112,9 +216,98 @@ impl State<TransitionData, SharedData> for GameState {
             game_data.player.draw();
             game_data.water.water.draw(payload.camera);
         }
+        // the camera moves, so the notice is placed relative to its target
+        let left = payload.camera.target.x - GAME_SIZE.x * 0.5f32 + 10f32;
+        let mut top = payload.camera.target.y - GAME_SIZE.y * 0.5f32 + 20f32;
+        for device in shared_data.input.midi.devices() {
+            if !device.is_connected() {
+                draw_text(
+                    format!("{} disconnected, waiting for it...", device.name()).as_str(),
+                    left,
+                    top,
+                    20f32,
+                    RED,
+                );
+                top += 20f32;
+            }
+        }
+        if shared_data.input.midi.is_recording() {
+            draw_text("REC", left, top, 20f32, RED);
+            top += 20f32;
+        }
+        if shared_data.input.midi.is_replaying() {
+            draw_text("REPLAY", left, top, 20f32, BLUE);
+            top += 20f32;
+        }
+        if shared_data.clock.is_playing() {
+            // flashes on every beat
+            let phase = shared_data.clock.beat_phase().unwrap_or(0f32);
+            let color = Color::new(1f32 - phase, 0.2f32, 0.2f32, 1f32);
+            let text = match shared_data.clock.bpm() {
+                Some(bpm) => format!("{:.0} BPM", bpm),
+                None => String::from("PLAYING"),
+            };
+            draw_text(text.as_str(), left, top, 20f32, color);
+            top += 20f32;
+        }
+        if let Some(latency) = &shared_data.latency {
+            for line in latency.summary() {
+                draw_text(line.as_str(), left, top, 12f32, DARKBLUE);
+                top += 10f32;
+            }
+        }
+        for (action, value, fader) in shared_data.input.pending_pickups() {
+            draw_pickup_gap(action, value, fader, left, top);
+            top += 14f32;
+        }
+        let top_left = payload.camera.target - GAME_SIZE * 0.5f32;
+        shared_data.monitor.draw(&shared_data.input, top_left);
     }
 }
 
+// a bar with the value the game holds in white and the fader in red,
+// the fader has to be moved across the gap to take over
+fn draw_pickup_gap(action: GameAction, value: f32, fader: Option<f32>, x: f32, y: f32) {
+    let width = 80f32;
+    let height = 6f32;
+    draw_text(format!("{:?}", action).as_str(), x, y, 14f32, BLACK);
+    let bar_x = x + 90f32;
+    let bar_y = y - height;
+    draw_rectangle(
+        bar_x,
+        bar_y,
+        width,
+        height,
+        Color::new(0f32, 0f32, 0f32, 0.4f32),
+    );
+    if let Some(fader) = fader {
+        let (from, to) = (value.min(fader), value.max(fader));
+        draw_rectangle(
+            bar_x + from * width,
+            bar_y,
+            (to - from) * width,
+            height,
+            Color::new(1f32, 0f32, 0f32, 0.4f32),
+        );
+        draw_line(
+            bar_x + fader * width,
+            bar_y - 2f32,
+            bar_x + fader * width,
+            bar_y + height + 2f32,
+            2f32,
+            RED,
+        );
+    }
+    draw_line(
+        bar_x + value * width,
+        bar_y - 2f32,
+        bar_x + value * width,
+        bar_y + height + 2f32,
+        2f32,
+        WHITE,
+    );
+}
+
 impl BootState {
     pub fn new(into_state: Box<dyn State<TransitionData, SharedData>>) -> Self {
         Self {
@@ -190,9 +383,7 @@ pub struct BootState {
 
 #[async_trait]
 impl State<TransitionData, SharedData> for BootState {
-    fn on_enter(&mut self, payload: StateManagerPayload<SharedData>) {
-        payload.shared_data.input.connect();
-    }
+    fn on_enter(&mut self, _payload: StateManagerPayload<SharedData>) {}
 
     async fn on_update(
         &mut self,
@@ -250,7 +441,8 @@ async fn main() {
     };
 
     let loadingstate_menu = Box::new(GameState::new());
-    let boot_state = Box::new(BootState::new(loadingstate_menu));
+    let device_picker_state = Box::new(DevicePickerState::new(loadingstate_menu));
+    let boot_state = Box::new(BootState::new(device_picker_state));
     let size = RenderTargetSize {
         width: GAME_SIZE.x as u32,
         height: GAME_SIZE.y as u32,
@@ -259,11 +451,78 @@ async fn main() {
         load_texture("resources/textures/transitions/transition_slide.png")
             .await
             .unwrap();
+    let config = Config::load();
+    let mut midi = MidiSource::new();
+    midi.set_velocity_threshold(config.velocity_threshold);
+    midi.set_pair_14_bit_cc(config.pair_14_bit_cc);
+    midi.set_mpe_members(config.mpe_members);
+    if config.virtual_midi_port {
+        midi.connect_virtual();
+    } else {
+        // ports are numbered in this order even when some aren't plugged in yet
+        for device in config
+            .midi_device
+            .iter()
+            .chain(config.more_midi_devices.iter())
+        {
+            if !midi.add_port_by_name(device) {
+                println!("no midi device matching {}, waiting for it", device);
+            }
+        }
+    }
+    // network ports come after the devices, devices picked on startup after them
+    if let Some(port) = config.rtp_midi_port {
+        midi.listen_rtp_midi(port);
+    }
+    if let Some(port) = config.osc_port {
+        midi.listen_osc(port);
+    }
+    if let Some(path) = &config.record {
+        midi.start_recording(path);
+    }
+    if let Some(path) = &config.replay {
+        midi.start_replay(path);
+    }
+    let mut feedback = MidiFeedback::new();
+    if config.virtual_feedback_port {
+        feedback.connect_virtual();
+    }
+    let latency = if config.latency_diagnostics {
+        let mut latency = LatencyMonitor::new();
+        if config.latency_loopback {
+            latency.start_loopback();
+        }
+        Some(latency)
+    } else {
+        None
+    };
+    let bindings_file = BindingsFile::load(BINDINGS_PATH);
+    let mut input = Input::new(midi);
+    input.set_bindings(bindings_file.bindings_for(&[]).clone());
+    // sources are asked in the order they're added, the file goes before the
+    // keyboard so its faders aren't hidden by the keyboard's resting values
+    if let Some(path) = &config.midi_file {
+        match MidiFile::load(path) {
+            Ok(file) => {
+                let source = MidiFileSource::new(file, config.loop_midi_file);
+                input.add_source(Box::new(source));
+            }
+            Err(err) => println!("can't play {}: {}", path, err),
+        }
+    }
+    input.add_source(Box::new(KeyboardSource::with_default_layout()));
     let shared_data = SharedData {
         texture_resources_optional: None,
         raw_image_resources_optional: None,
         sound_resources_optional: None,
-        input: Input::new(),
+        input,
+        feedback,
+        latency,
+        monitor: MidiMonitor::new(),
+        clock: BeatClock::new(),
+        config,
+        bindings_file,
+        device_events: Vec::new(),
     };
 
     let mut transition_texture_map = HashMap::new();
@@ -0,0 +1,309 @@
+use crate::bindings::*;
+use crate::input::*;
+use crate::midi_message::*;
+use std::collections::HashMap;
+
+// name of the port other software can listen on, see Config::virtual_feedback_port
+pub const VIRTUAL_FEEDBACK_PORT: &str = "midi_game feedback";
+
+// sends game state back to the controller to light pads and move motorized faders
+// what is sent where comes from the feedback list of the bindings profile
+pub struct MidiFeedback {
+    // optional because it's consumed by the connection
+    // also None when midi isn't available at all on this machine
+    midi_output: Option<midir::MidiOutput>,
+    connection: Option<midir::MidiOutputConnection>,
+    // name of the port connected to, None for the virtual port
+    port_name: Option<String>,
+    feedback: Vec<Feedback>,
+    // what every control should be showing
+    values: HashMap<ControlId, u8>,
+    // what was last sent, only changes are sent
+    sent: HashMap<ControlId, u8>,
+}
+
+impl MidiFeedback {
+    pub fn new() -> Self {
+        let midi_output = match midir::MidiOutput::new("Feedback") {
+            Ok(midi_output) => Some(midi_output),
+            Err(err) => {
+                println!("midi output unavailable: {}", err);
+                None
+            }
+        };
+        Self {
+            midi_output,
+            connection: None,
+            port_name: None,
+            feedback: Vec::new(),
+            values: HashMap::new(),
+            sent: HashMap::new(),
+        }
+    }
+
+    pub fn set_feedback(&mut self, feedback: &[Feedback]) {
+        self.feedback = feedback.to_vec();
+        self.values.clear();
+        self.sent.clear();
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    // connects to the first output port containing name, ignoring case
+    // does nothing when already connected to it
+    pub fn connect(&mut self, name: &str) {
+        let name = name.to_lowercase();
+        if let Some(port_name) = &self.port_name {
+            if self.is_connected() && port_name.to_lowercase().contains(&name) {
+                return;
+            }
+        }
+        self.disconnect();
+        let midi_output = match self.midi_output.take() {
+            Some(midi_output) => midi_output,
+            None => return,
+        };
+        let port = midi_output.ports().into_iter().find_map(|port| {
+            let port_name = midi_output.port_name(&port).ok()?;
+            if port_name.to_lowercase().contains(&name) {
+                Some((port, port_name))
+            } else {
+                None
+            }
+        });
+        let (port, port_name) = match port {
+            Some(port) => port,
+            None => {
+                self.midi_output = Some(midi_output);
+                return;
+            }
+        };
+        match midi_output.connect(&port, port_name.as_str()) {
+            Ok(connection) => {
+                self.connection = Some(connection);
+                self.port_name = Some(port_name);
+            }
+            Err(err) => {
+                println!("can't connect to midi output {}: {}", port_name, err);
+                self.midi_output = Some(err.into_inner());
+            }
+        }
+    }
+
+    // a port other software can connect to, for checking what the game sends
+    // with aseqdump or similar without a controller
+    #[cfg(unix)]
+    pub fn connect_virtual(&mut self) {
+        use midir::os::unix::VirtualOutput;
+        self.disconnect();
+        let midi_output = match self.midi_output.take() {
+            Some(midi_output) => midi_output,
+            None => return,
+        };
+        match midi_output.create_virtual(VIRTUAL_FEEDBACK_PORT) {
+            Ok(connection) => self.connection = Some(connection),
+            Err(err) => {
+                println!("can't create virtual midi output: {}", err);
+                self.midi_output = Some(err.into_inner());
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect_virtual(&mut self) {
+        println!("virtual midi ports aren't supported on this platform");
+    }
+
+    pub fn disconnect(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.midi_output = Some(connection.close());
+        }
+        self.port_name = None;
+        // a reconnected device starts dark, so everything is sent again
+        self.sent.clear();
+    }
+
+    // fraction 0 sends the off value, 1 the on value
+    pub fn set(&mut self, source: FeedbackSource, fraction: f32) {
+        let fraction = fraction.clamp(0f32, 1f32);
+        for feedback in self
+            .feedback
+            .iter()
+            .filter(|feedback| feedback.source == source)
+        {
+            let range = feedback.on as f32 - feedback.off as f32;
+            let value = (feedback.off as f32 + range * fraction).round() as u8;
+            self.values.insert(feedback.control, value.min(127));
+        }
+    }
+
+    pub fn set_on(&mut self, source: FeedbackSource, is_on: bool) {
+        self.set(source, if is_on { 1f32 } else { 0f32 });
+    }
+
+    // call once per frame after everything is set
+    pub fn send(&mut self) {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return,
+        };
+        let result = send_changes(&self.values, &mut self.sent, |bytes| connection.send(bytes));
+        // the device was probably unplugged, it's connected again when it comes back
+        if let Err(err) = result {
+            println!("can't send midi feedback: {}", err);
+            self.disconnect();
+        }
+    }
+}
+
+// sends the values that changed since they were last sent, stops at the first error
+fn send_changes<E>(
+    values: &HashMap<ControlId, u8>,
+    sent: &mut HashMap<ControlId, u8>,
+    mut send: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    for (id, value) in values.iter() {
+        if sent.get(id) == Some(value) {
+            continue;
+        }
+        if let Some(message) = feedback_message(*id, *value) {
+            send(&message.to_bytes())?;
+            sent.insert(*id, *value);
+        }
+    }
+    Ok(())
+}
+
+fn feedback_message(id: ControlId, value: u8) -> Option<MidiMessage> {
+    match id.kind {
+        ControlKind::Note => Some(MidiMessage::NoteOn {
+            channel: id.channel,
+            note: id.number as u8,
+            velocity: value,
+        }),
+        ControlKind::ControlChange => Some(MidiMessage::ControlChange {
+            channel: id.channel,
+            controller: id.number as u8,
+            value,
+        }),
+        // nothing else lights up or moves
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LATCH_PAD: ControlId = ControlId::note(9, 37);
+    const FADER: ControlId = ControlId::cc(0, 0);
+
+    fn feedback() -> MidiFeedback {
+        let mut feedback = MidiFeedback::new();
+        feedback.set_feedback(&[
+            Feedback {
+                source: FeedbackSource::LatchOpen,
+                control: LATCH_PAD,
+                on: 5,
+                off: 1,
+            },
+            Feedback {
+                source: FeedbackSource::Action(GameAction::JumpStrength),
+                control: FADER,
+                on: 127,
+                off: 0,
+            },
+        ]);
+        feedback
+    }
+
+    // what send would send, without a port
+    fn sent_bytes(feedback: &mut MidiFeedback) -> Vec<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let result: Result<(), ()> =
+            send_changes(&feedback.values, &mut feedback.sent, |message| {
+                bytes.push(message.to_vec());
+                Ok(())
+            });
+        assert!(result.is_ok());
+        bytes.sort();
+        bytes
+    }
+
+    #[test]
+    fn values_map_between_off_and_on() {
+        let mut feedback = feedback();
+        feedback.set_on(FeedbackSource::LatchOpen, true);
+        feedback.set(FeedbackSource::Action(GameAction::JumpStrength), 0.5f32);
+        assert_eq!(
+            sent_bytes(&mut feedback),
+            vec![vec![0x99, 37, 5], vec![0xB0, 0, 64]]
+        );
+        feedback.set_on(FeedbackSource::LatchOpen, false);
+        // out of range fractions are clamped
+        feedback.set(FeedbackSource::Action(GameAction::JumpStrength), 2f32);
+        assert_eq!(
+            sent_bytes(&mut feedback),
+            vec![vec![0x99, 37, 1], vec![0xB0, 0, 127]]
+        );
+    }
+
+    #[test]
+    fn only_changes_are_sent() {
+        let mut feedback = feedback();
+        feedback.set_on(FeedbackSource::LatchOpen, true);
+        assert_eq!(sent_bytes(&mut feedback).len(), 1);
+        feedback.set_on(FeedbackSource::LatchOpen, true);
+        assert!(sent_bytes(&mut feedback).is_empty());
+        // a new profile starts over and sends everything again
+        let profile = feedback.feedback.clone();
+        feedback.set_feedback(&profile);
+        feedback.set_on(FeedbackSource::LatchOpen, true);
+        assert_eq!(sent_bytes(&mut feedback), vec![vec![0x99, 37, 5]]);
+    }
+
+    // reads what the game sends from the virtual port, like aseqdump would
+    #[test]
+    #[ignore = "needs a midi backend, like the alsa sequencer"]
+    #[cfg(target_os = "linux")]
+    fn feedback_reaches_the_virtual_port() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let mut feedback = feedback();
+        feedback.connect_virtual();
+        assert!(feedback.is_connected());
+
+        let midi_input = midir::MidiInput::new("test listener").unwrap();
+        let port = midi_input
+            .ports()
+            .into_iter()
+            .find(|port| {
+                midi_input
+                    .port_name(port)
+                    .unwrap()
+                    .contains(VIRTUAL_FEEDBACK_PORT)
+            })
+            .expect("virtual feedback port not found");
+        let (sender, receiver) = mpsc::channel();
+        let _connection = midi_input
+            .connect(
+                &port,
+                "test listener",
+                move |_, bytes, _| sender.send(bytes.to_vec()).unwrap(),
+                (),
+            )
+            .unwrap();
+        feedback.set_on(FeedbackSource::LatchOpen, true);
+        feedback.send();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(1)),
+            Ok(vec![0x99, 37, 5])
+        );
+        // unchanged values aren't sent again
+        feedback.send();
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::midi_message::*;
+use std::fs;
+
+// microseconds per quarter note until the file sets a tempo, 120 bpm
+const DEFAULT_TEMPO: u32 = 500_000;
+const META: u8 = 0xFF;
+const META_TEMPO: u8 = 0x51;
+const META_END_OF_TRACK: u8 = 0x2F;
+
+// reads big endian numbers and variable length quantities out of the file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.position + len;
+        if end > self.bytes.len() {
+            return Err(String::from("unexpected end of file"));
+        }
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, String> {
+        match self.bytes.get(self.position) {
+            Some(byte) => Ok(*byte),
+            None => Err(String::from("unexpected end of file")),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // 7 bits per byte, the high bit is set on every byte but the last
+    fn variable_length(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = value << 7 | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(String::from("variable length quantity longer than 4 bytes"))
+    }
+}
+
+// how ticks are turned into time
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Division {
+    TicksPerQuarter(u16),
+    // frames per second and ticks per frame, tempo changes don't apply
+    Timecode(u8, u8),
+}
+
+enum TrackEvent {
+    Message(MidiMessage),
+    // microseconds per quarter note
+    Tempo(u32),
+}
+
+// every channel message of a standard midi file, all tracks merged and timed in seconds
+pub struct MidiFile {
+    // sorted by time
+    pub events: Vec<(f64, MidiMessage)>,
+}
+
+impl MidiFile {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|err| err.to_string())?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(4)? != b"MThd" {
+            return Err(String::from("not a standard midi file"));
+        }
+        let header_len = reader.u32()? as usize;
+        let mut header = Reader::new(reader.take(header_len)?);
+        let _format = header.u16()?;
+        let track_count = header.u16()?;
+        let division = match header.u16()? {
+            division if division & 0x8000 != 0 => {
+                // the upper byte is the negative frame rate
+                Division::Timecode(((division >> 8) as u8).wrapping_neg(), division as u8)
+            }
+            division => Division::TicksPerQuarter(division.max(1)),
+        };
+
+        // (tick, track, event), the track keeps events of the same tick in file order
+        let mut events = Vec::new();
+        let mut track = 0;
+        while !reader.is_empty() && track < track_count {
+            let id = reader.take(4)?;
+            let chunk_len = reader.u32()? as usize;
+            let chunk = reader.take(chunk_len)?;
+            // unknown chunks are allowed and skipped
+            if id != b"MTrk" {
+                continue;
+            }
+            for (tick, event) in Self::parse_track(chunk)? {
+                events.push((tick, track, event));
+            }
+            track += 1;
+        }
+        events.sort_by_key(|(tick, track, _)| (*tick, *track));
+
+        let mut tempo = DEFAULT_TEMPO;
+        let mut last_tick = 0u64;
+        let mut seconds = 0f64;
+        let mut timed = Vec::new();
+        for (tick, _, event) in events {
+            let seconds_per_tick = match division {
+                Division::TicksPerQuarter(ticks) => tempo as f64 / 1_000_000f64 / ticks as f64,
+                Division::Timecode(fps, ticks) => 1f64 / (fps.max(1) as f64 * ticks.max(1) as f64),
+            };
+            seconds += (tick - last_tick) as f64 * seconds_per_tick;
+            last_tick = tick;
+            match event {
+                TrackEvent::Message(message) => timed.push((seconds, message)),
+                TrackEvent::Tempo(new_tempo) => tempo = new_tempo,
+            }
+        }
+        Ok(Self { events: timed })
+    }
+
+    fn parse_track(bytes: &[u8]) -> Result<Vec<(u64, TrackEvent)>, String> {
+        let mut reader = Reader::new(bytes);
+        let mut parser = MidiParser::new();
+        let mut events = Vec::new();
+        let mut tick = 0u64;
+        let mut running_status = None;
+        while !reader.is_empty() {
+            tick += reader.variable_length()? as u64;
+            let status = match reader.peek()? {
+                byte if byte & 0x80 != 0 => {
+                    reader.byte()?;
+                    byte
+                }
+                _ => running_status.ok_or_else(|| String::from("data byte without status"))?,
+            };
+            match status {
+                META => {
+                    let kind = reader.byte()?;
+                    let len = reader.variable_length()? as usize;
+                    let data = reader.take(len)?;
+                    running_status = None;
+                    match kind {
+                        META_TEMPO if data.len() == 3 => {
+                            let tempo =
+                                (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+                            events.push((tick, TrackEvent::Tempo(tempo)));
+                        }
+                        META_END_OF_TRACK => break,
+                        _ => {}
+                    }
+                }
+                // sysex is skipped
+                0xF0 | 0xF7 => {
+                    let len = reader.variable_length()? as usize;
+                    reader.take(len)?;
+                    running_status = None;
+                }
+                0xF1..=0xFE => return Err(format!("unexpected status {:02x} in track", status)),
+                _ => {
+                    running_status = Some(status);
+                    let mut message = vec![status];
+                    message.extend_from_slice(reader.take(data_len(status))?);
+                    for message in parser.parse(&message) {
+                        events.push((tick, TrackEvent::Message(message)));
+                    }
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(events: &[u8]) -> Vec<u8> {
+        let mut chunk = b"MTrk".to_vec();
+        chunk.extend_from_slice(&(events.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(events);
+        chunk
+    }
+
+    #[test]
+    fn tracks_are_merged_and_timed_with_the_tempo() {
+        // format 1, 2 tracks, 96 ticks per quarter note
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 6, 0, 1, 0, 2, 0, 96]);
+        // 60 bpm from the start
+        bytes.extend(track(&[
+            0, 0xFF, 0x51, 3, 0x0F, 0x42, 0x40, 0, 0xFF, 0x2F, 0,
+        ]));
+        // note on after a quarter, its note off uses running status after
+        // another 192 ticks, written as the variable length 0x81 0x40
+        bytes.extend(track(&[
+            96, 0x90, 64, 100, 0x81, 0x40, 64, 0, 0, 0xFF, 0x2F, 0,
+        ]));
+        let file = MidiFile::parse(&bytes).unwrap();
+        assert_eq!(
+            file.events,
+            vec![
+                (
+                    1f64,
+                    MidiMessage::NoteOn {
+                        channel: 0,
+                        note: 64,
+                        velocity: 100
+                    }
+                ),
+                (
+                    3f64,
+                    MidiMessage::NoteOn {
+                        channel: 0,
+                        note: 64,
+                        velocity: 0
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        assert!(MidiFile::parse(b"RIFF....").is_err());
+        assert!(MidiFile::parse(b"MThd\0\0\0\x06\0\0\0\x01").is_err());
+    }
+
+    #[test]
+    fn timecode_division_is_read_as_a_frame_rate() {
+        // -25 frames per second, 40 ticks per frame
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1, 0xE7, 40]);
+        // a note after one frame
+        bytes.extend(track(&[40, 0x90, 64, 100, 0, 0xFF, 0x2F, 0]));
+        let file = MidiFile::parse(&bytes).unwrap();
+        assert_eq!(file.events.len(), 1);
+        assert!((file.events[0].0 - 0.04f64).abs() < 1e-9);
+        // out of range frame rates don't overflow
+        assert!(MidiFile::parse(b"MThd\0\0\0\x06\0\0\0\0\x80\0").is_ok());
+    }
+}
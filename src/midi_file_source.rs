@@ -0,0 +1,139 @@
+use crate::bindings::*;
+use crate::control_state::*;
+use crate::event_queue::*;
+use crate::expression::*;
+use crate::input::*;
+use crate::midi_file::*;
+
+// plays a standard midi file as if it was a controller, for demos, attract mode
+// and letting the game run for hours without anyone at the controller
+// notes and controls go through the bindings like live ones, so the file needs to
+// use the channels and numbers the bindings expect
+pub struct MidiFileSource {
+    file: MidiFile,
+    // index of the next event to play
+    next: usize,
+    time: f64,
+    // starts over after the last event
+    is_looping: bool,
+    controls: ControlStates,
+}
+
+impl MidiFileSource {
+    pub fn new(file: MidiFile, is_looping: bool) -> Self {
+        Self {
+            file,
+            next: 0,
+            time: 0f64,
+            is_looping,
+            controls: ControlStates::new(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.file.events.len()
+    }
+}
+
+impl InputSource for MidiFileSource {
+    fn is_button_held(&self, id: ControlId) -> bool {
+        self.controls.get(id).is_some_and(|state| state.is_held())
+    }
+
+    fn is_button_pressed(&self, id: ControlId) -> bool {
+        self.controls
+            .get(id)
+            .is_some_and(|state| state.is_pressed())
+    }
+
+    fn is_button_released(&self, id: ControlId) -> bool {
+        self.controls
+            .get(id)
+            .is_some_and(|state| state.is_released())
+    }
+
+    fn button_velocity(&self, id: ControlId) -> Option<f32> {
+        self.controls.get(id).map(|state| state.velocity())
+    }
+
+    fn fraction(&self, id: ControlId) -> Option<f32> {
+        self.controls.get(id).and_then(|state| state.fraction())
+    }
+
+    fn fraction_reached_limit(
+        &self,
+        id: ControlId,
+        fraction: f32,
+        limit: SliderLimitCheck,
+    ) -> bool {
+        self.controls
+            .get(id)
+            .is_some_and(|state| state.reached_limit(fraction, limit))
+    }
+
+    fn trigger_reached(&self, id: ControlId, _trigger: &Trigger, limit: SliderLimitCheck) -> bool {
+        self.controls
+            .get(id)
+            .is_some_and(|state| state.reached_trigger(limit))
+    }
+
+    fn update(&mut self, dt: f32) {
+        if self.is_finished() && self.is_looping && !self.file.events.is_empty() {
+            self.next = 0;
+            self.time = 0f64;
+            // notes still held at the end are let go
+            self.controls.clear();
+        }
+        self.controls.begin_frame();
+        self.time += dt as f64;
+        while let Some((seconds, message)) = self.file.events.get(self.next) {
+            if *seconds > self.time {
+                break;
+            }
+            self.controls.apply(&InputEvent {
+                timestamp: (*seconds * 1_000_000f64) as u64,
+                port: 0,
+                message: *message,
+                received: None,
+            });
+            self.next += 1;
+        }
+    }
+
+    fn note_expression(&self, id: ControlId) -> Option<NoteExpression> {
+        self.controls.expression().note(id)
+    }
+
+    fn set_bindings(&mut self, bindings: &Bindings) {
+        self.controls.set_bindings(bindings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_message::*;
+
+    #[test]
+    fn looping_file_presses_again_every_loop() {
+        let pad = ControlId::note(0, 64);
+        let note = |velocity| MidiMessage::NoteOn {
+            channel: 0,
+            note: 64,
+            velocity,
+        };
+        let file = MidiFile {
+            events: vec![(0.5f64, note(100)), (1f64, note(0))],
+        };
+        let mut source = MidiFileSource::new(file, true);
+        let mut presses = 0;
+        // ten loops at 60 frames per second
+        for _ in 0..600 {
+            source.update(1f32 / 60f32);
+            if source.is_button_pressed(pad) {
+                presses += 1;
+            }
+        }
+        assert_eq!(presses, 10);
+    }
+}
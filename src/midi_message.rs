@@ -0,0 +1,336 @@
+use crate::sysex::*;
+use std::fmt;
+
+#[derive(Eq, Clone, Debug, Copy, PartialEq)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    // 14 bit value, 8192 is center
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    // system real-time from a sequencer, 24 clocks per quarter note while it's running
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    // song position pointer, counted in sixteenth notes since the start of the song
+    SongPosition {
+        beats: u16,
+    },
+}
+
+const SONG_POSITION: u8 = 0xF2;
+
+impl MidiMessage {
+    // system messages don't belong to a channel
+    pub fn channel(&self) -> Option<u8> {
+        use MidiMessage::*;
+        match *self {
+            NoteOff { channel, .. }
+            | NoteOn { channel, .. }
+            | PolyPressure { channel, .. }
+            | ControlChange { channel, .. }
+            | ProgramChange { channel, .. }
+            | ChannelPressure { channel, .. }
+            | PitchBend { channel, .. } => Some(channel),
+            TimingClock | Start | Continue | Stop | SongPosition { .. } => None,
+        }
+    }
+
+    // status byte followed by the data bytes, without running status
+    pub fn to_bytes(self) -> Vec<u8> {
+        use MidiMessage::*;
+        let channel = self.channel().unwrap_or(0) & 0x0F;
+        match self {
+            NoteOff { note, velocity, .. } => vec![0x80 | channel, note, velocity],
+            NoteOn { note, velocity, .. } => vec![0x90 | channel, note, velocity],
+            PolyPressure { note, pressure, .. } => vec![0xA0 | channel, note, pressure],
+            ControlChange {
+                controller, value, ..
+            } => vec![0xB0 | channel, controller, value],
+            ProgramChange { program, .. } => vec![0xC0 | channel, program],
+            ChannelPressure { pressure, .. } => vec![0xD0 | channel, pressure],
+            PitchBend { value, .. } => {
+                vec![
+                    0xE0 | channel,
+                    (value & 0x7F) as u8,
+                    (value >> 7 & 0x7F) as u8,
+                ]
+            }
+            TimingClock => vec![0xF8],
+            Start => vec![0xFA],
+            Continue => vec![0xFB],
+            Stop => vec![0xFC],
+            SongPosition { beats } => vec![
+                SONG_POSITION,
+                (beats & 0x7F) as u8,
+                (beats >> 7 & 0x7F) as u8,
+            ],
+        }
+    }
+
+    // active sensing and reset aren't used, None for those
+    fn from_real_time(status: u8) -> Option<Self> {
+        match status {
+            0xF8 => Some(MidiMessage::TimingClock),
+            0xFA => Some(MidiMessage::Start),
+            0xFB => Some(MidiMessage::Continue),
+            0xFC => Some(MidiMessage::Stop),
+            _ => None,
+        }
+    }
+
+    fn from_status(status: u8, data: &[u8]) -> Option<Self> {
+        if status == SONG_POSITION {
+            return Some(MidiMessage::SongPosition {
+                beats: data[0] as u16 | (data[1] as u16) << 7,
+            });
+        }
+        let channel = status & 0x0F;
+        let message = match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff {
+                channel,
+                note: data[0],
+                velocity: data[1],
+            },
+            0x90 => MidiMessage::NoteOn {
+                channel,
+                note: data[0],
+                velocity: data[1],
+            },
+            0xA0 => MidiMessage::PolyPressure {
+                channel,
+                note: data[0],
+                pressure: data[1],
+            },
+            0xB0 => MidiMessage::ControlChange {
+                channel,
+                controller: data[0],
+                value: data[1],
+            },
+            0xC0 => MidiMessage::ProgramChange {
+                channel,
+                program: data[0],
+            },
+            0xD0 => MidiMessage::ChannelPressure {
+                channel,
+                pressure: data[0],
+            },
+            0xE0 => MidiMessage::PitchBend {
+                channel,
+                value: data[0] as u16 | (data[1] as u16) << 7,
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+// channel is shown 1 based like on the hardware
+impl fmt::Display for MidiMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use MidiMessage::*;
+        if let Some(channel) = self.channel() {
+            write!(f, "ch {} ", channel + 1)?;
+        }
+        match *self {
+            NoteOff { note, velocity, .. } => write!(f, "note off {} vel {}", note, velocity),
+            NoteOn { note, velocity, .. } => write!(f, "note on {} vel {}", note, velocity),
+            PolyPressure { note, pressure, .. } => {
+                write!(f, "poly pressure {} {}", note, pressure)
+            }
+            ControlChange {
+                controller, value, ..
+            } => write!(f, "cc {} {}", controller, value),
+            ProgramChange { program, .. } => write!(f, "program {}", program),
+            ChannelPressure { pressure, .. } => write!(f, "pressure {}", pressure),
+            PitchBend { value, .. } => write!(f, "pitch bend {}", value),
+            TimingClock => write!(f, "clock"),
+            Start => write!(f, "start"),
+            Continue => write!(f, "continue"),
+            Stop => write!(f, "stop"),
+            SongPosition { beats } => write!(f, "song position {}", beats),
+        }
+    }
+}
+
+// amount of data bytes following a channel voice or song position status byte
+pub fn data_len(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+// turns raw bytes into MidiMessages, remembering the last status byte
+// so messages sent with running status can be decoded
+pub struct MidiParser {
+    running_status: Option<u8>,
+    // sysex being read, it can be split over several calls
+    sysex: Option<Vec<u8>>,
+    // complete sysex messages, from F0 to F7, waiting for take_sysex
+    finished_sysex: Vec<Vec<u8>>,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self {
+            running_status: None,
+            sysex: None,
+            finished_sysex: Vec::new(),
+        }
+    }
+
+    // sysex messages parse finished since the last call
+    pub fn take_sysex(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.finished_sysex)
+    }
+
+    // unknown, truncated or malformed data is skipped, never panics
+    pub fn parse(&mut self, bytes: &[u8]) -> Vec<MidiMessage> {
+        let mut messages = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = bytes[i];
+            if let Some(mut sysex) = self.sysex.take() {
+                if byte & 0x80 == 0 {
+                    sysex.push(byte);
+                    i += 1;
+                    // too long to be anything the game understands, the rest is skipped as stray data
+                    if sysex.len() <= MAX_SYSEX_LEN {
+                        self.sysex = Some(sysex);
+                    }
+                    continue;
+                }
+                if byte == SYSEX_END {
+                    sysex.push(byte);
+                    self.finished_sysex.push(sysex);
+                    i += 1;
+                    continue;
+                }
+                // real-time can be in the middle of it, any other status byte cuts it off
+                if byte >= 0xF8 {
+                    self.sysex = Some(sysex);
+                }
+            }
+            let status = if byte & 0x80 != 0 {
+                i += 1;
+                match byte {
+                    // system real-time can be interleaved anywhere and leaves running status intact
+                    0xF8..=0xFF => {
+                        messages.extend(MidiMessage::from_real_time(byte));
+                        continue;
+                    }
+                    SONG_POSITION => {
+                        self.running_status = None;
+                        byte
+                    }
+                    SYSEX_START => {
+                        self.running_status = None;
+                        self.sysex = Some(vec![byte]);
+                        continue;
+                    }
+                    // other system common cancel running status, skip their data
+                    0xF1..=0xF7 => {
+                        self.running_status = None;
+                        while i < bytes.len() && bytes[i] & 0x80 == 0 {
+                            i += 1;
+                        }
+                        continue;
+                    }
+                    _ => {
+                        self.running_status = Some(byte);
+                        byte
+                    }
+                }
+            } else {
+                match self.running_status {
+                    Some(status) => status,
+                    None => {
+                        // stray data byte, nothing to attach it to
+                        i += 1;
+                        continue;
+                    }
+                }
+            };
+
+            let len = data_len(status);
+            let mut data = [0u8; 2];
+            let mut count = 0;
+            while count < len && i < bytes.len() {
+                let byte = bytes[i];
+                if byte >= 0xF8 {
+                    // clocks arrive in the middle of other messages
+                    messages.extend(MidiMessage::from_real_time(byte));
+                } else if byte & 0x80 != 0 {
+                    // a status byte showed up before the message was complete
+                    break;
+                } else {
+                    data[count] = byte;
+                    count += 1;
+                }
+                i += 1;
+            }
+            // truncated, or cut off by the status byte i now points at
+            if count < len {
+                continue;
+            }
+            if let Some(message) = MidiMessage::from_status(status, &data[..len]) {
+                messages.push(message);
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_in_the_middle_of_a_message_is_not_lost() {
+        let mut parser = MidiParser::new();
+        assert_eq!(
+            parser.parse(&[0x90, 0xF8, 64, 0xFA, 100, 0xF2, 0x10, 0xF8, 0x01]),
+            vec![
+                MidiMessage::TimingClock,
+                MidiMessage::Start,
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 64,
+                    velocity: 100
+                },
+                MidiMessage::TimingClock,
+                MidiMessage::SongPosition { beats: 0x90 },
+            ]
+        );
+    }
+}
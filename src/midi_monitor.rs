@@ -0,0 +1,124 @@
+use crate::bindings::*;
+use crate::constants::*;
+use crate::input::*;
+use crate::midi_message::*;
+use macroquad::prelude::*;
+use std::collections::VecDeque;
+
+// how many of the latest messages are listed
+const RECENT_MESSAGES: usize = 16;
+const LINE_HEIGHT: f32 = 11f32;
+const FONT_SIZE: f32 = 12f32;
+
+// on-screen replacement for printing every message, toggled with F3
+// lists the latest messages and what every bound control is doing
+pub struct MidiMonitor {
+    pub is_visible: bool,
+    // newest first
+    recent: VecDeque<String>,
+}
+
+impl MidiMonitor {
+    pub fn new() -> Self {
+        Self {
+            is_visible: false,
+            recent: VecDeque::with_capacity(RECENT_MESSAGES),
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.is_visible = !self.is_visible;
+    }
+
+    // call every frame after the input is updated, messages are kept while hidden
+    // so the list is already filled when it's opened
+    pub fn update(&mut self, input: &Input) {
+        for event in input.midi.events_this_frame() {
+            // 24 clocks per beat would push everything else off the list
+            if event.message == MidiMessage::TimingClock {
+                continue;
+            }
+            if self.recent.len() == RECENT_MESSAGES {
+                self.recent.pop_back();
+            }
+            let line = match event.port {
+                0 => event.message.to_string(),
+                port => format!("port {} {}", port, event.message),
+            };
+            self.recent.push_front(line);
+        }
+        for (port, sysex) in input.midi.sysex_this_frame() {
+            if self.recent.len() == RECENT_MESSAGES {
+                self.recent.pop_back();
+            }
+            let line = match port {
+                0 => format!("sysex {} bytes", sysex.len()),
+                port => format!("port {} sysex {} bytes", port, sysex.len()),
+            };
+            self.recent.push_front(line);
+        }
+    }
+
+    // top_left is where the top left corner of the screen is in the game camera
+    pub fn draw(&self, input: &Input, top_left: Vec2) {
+        if !self.is_visible {
+            return;
+        }
+        let x = top_left.x;
+        let y = top_left.y;
+        draw_rectangle(
+            x,
+            y,
+            GAME_SIZE.x,
+            GAME_SIZE.y,
+            Color::new(0f32, 0f32, 0f32, 0.75f32),
+        );
+        draw_text("MIDI MONITOR  f3: hide", x + 6f32, y + 14f32, 16f32, WHITE);
+
+        let mut line_y = y + 30f32;
+        if self.recent.is_empty() {
+            draw_text("no messages yet", x + 6f32, line_y, FONT_SIZE, GRAY);
+        }
+        for (i, line) in self.recent.iter().enumerate() {
+            // older messages fade out
+            let alpha = 1f32 - i as f32 / RECENT_MESSAGES as f32 * 0.7f32;
+            let color = Color::new(0.6f32, 1f32, 0.6f32, alpha);
+            draw_text(line.as_str(), x + 6f32, line_y, FONT_SIZE, color);
+            line_y += LINE_HEIGHT;
+        }
+
+        let column_x = x + GAME_SIZE.x * 0.45f32;
+        let bar_x = column_x + 200f32;
+        let bar_width = GAME_SIZE.x - (bar_x - x) - 8f32;
+        let mut line_y = y + 30f32;
+        for action in GameAction::ALL.iter() {
+            let (control, fraction, is_held) = match input.bindings().control(*action) {
+                Some(id) => (
+                    id.to_string(),
+                    input.get_fraction(id),
+                    input.is_button_held(id) || input.is_button_pressed(id),
+                ),
+                None => (String::from("-"), 0f32, false),
+            };
+            draw_text(
+                format!("{:?}", action).as_str(),
+                column_x,
+                line_y,
+                FONT_SIZE,
+                WHITE,
+            );
+            draw_text(control.as_str(), column_x + 80f32, line_y, FONT_SIZE, GRAY);
+            let bar_y = line_y - 7f32;
+            draw_rectangle(bar_x, bar_y, bar_width, 7f32, DARKGRAY);
+            // buttons light up while held, faders show their value
+            if action.is_button() {
+                if is_held {
+                    draw_rectangle(bar_x, bar_y, bar_width, 7f32, YELLOW);
+                }
+            } else {
+                draw_rectangle(bar_x, bar_y, bar_width * fraction, 7f32, SKYBLUE);
+            }
+            line_y += LINE_HEIGHT;
+        }
+    }
+}
@@ -0,0 +1,654 @@
+use crate::bindings::*;
+use crate::control_decoder::*;
+use crate::control_state::*;
+use crate::event_queue::*;
+use crate::expression::*;
+use crate::input::*;
+use crate::midi_message::*;
+use crate::network_midi::*;
+use crate::osc;
+use crate::recording::*;
+use crate::rtp_midi;
+use crate::sysex::*;
+use std::string::*;
+use std::time::Instant;
+
+// how often ports are checked for unplugged or replugged devices
+const RESCAN_INTERVAL: f32 = 1f32;
+// name of the port the game makes when asked to, see Config::virtual_midi_port
+pub const VIRTUAL_INPUT_PORT: &str = "midi_game in";
+
+// one midi port the game reads from, its events are tagged with its port number
+pub struct MidiDevice {
+    // as it was added, the port is looked up by it on every connect so it survives
+    // being replugged, matched ignoring case
+    name: String,
+    // full name of the port it's connected to
+    port_name: Option<String>,
+    // bindings tell devices apart by it, devices are numbered in the order they're added
+    port: u8,
+    // optional because it needs to be consumed and sent to the connection thread
+    // also None when midi isn't available at all on this machine
+    midi_input: Option<midir::MidiInput>,
+    connection: Option<midir::MidiInputConnection<()>>,
+    // the game made its own port for other software to connect to, it can't be unplugged
+    is_virtual: bool,
+    // from the device's reply to the inquiry sent on connect, not every device answers
+    identity: Option<DeviceIdentity>,
+    // a reply came in since the last poll_devices
+    is_identity_new: bool,
+}
+
+impl MidiDevice {
+    fn new(name: String, port: u8) -> Self {
+        let midi_input = match midir::MidiInput::new("Input device") {
+            Ok(midi_input) => Some(midi_input),
+            Err(err) => {
+                println!("midi unavailable: {}", err);
+                None
+            }
+        };
+        Self {
+            name,
+            port_name: None,
+            port,
+            midi_input,
+            connection: None,
+            is_virtual: false,
+            identity: None,
+            is_identity_new: false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.port_name.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    pub fn identity(&self) -> Option<&DeviceIdentity> {
+        self.identity.as_ref()
+    }
+
+    pub fn is_virtual(&self) -> bool {
+        self.is_virtual
+    }
+
+    fn matches(&self, port_name: &str) -> bool {
+        port_name.to_lowercase().contains(&self.name.to_lowercase())
+    }
+
+    // does nothing when it isn't plugged in
+    fn connect(&mut self, port_names: &[String], queue: &EventQueue, sysex_queue: &SysExQueue) {
+        if self.connection.is_some() || self.is_virtual {
+            return;
+        }
+        let port_name = match port_names.iter().find(|port_name| self.matches(port_name)) {
+            Some(port_name) => port_name.clone(),
+            None => return,
+        };
+        let midi_input = match self.midi_input.take() {
+            Some(midi_input) => midi_input,
+            None => return,
+        };
+        let input_port = midi_input
+            .ports()
+            .into_iter()
+            .find(|port| midi_input.port_name(port).ok().as_ref() == Some(&port_name));
+        let input_port = match input_port {
+            Some(input_port) => input_port,
+            None => {
+                self.midi_input = Some(midi_input);
+                return;
+            }
+        };
+        // consume midi_input because it will be sent to thread
+        let callback = on_message(self.port, queue, sysex_queue);
+        let result = midi_input.connect(&input_port, port_name.as_str(), callback, ());
+        match result {
+            Ok(connection) => {
+                self.connection = Some(connection);
+                send_device_inquiry(&port_name);
+                self.port_name = Some(port_name);
+            }
+            Err(err) => {
+                println!("can't connect to midi device {}: {}", port_name, err);
+                // get midi_input back so we can try again
+                self.midi_input = Some(err.into_inner());
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            let (midi_input, _) = connection.close();
+            self.midi_input = Some(midi_input);
+        }
+        self.identity = None;
+        self.is_identity_new = false;
+    }
+}
+
+// sysex with the port it came from
+type SysExQueue = EventQueue<(u8, Vec<u8>)>;
+
+// runs on the midi thread, parses and pushes to the queue
+fn on_message(
+    port: u8,
+    queue: &EventQueue,
+    sysex_queue: &SysExQueue,
+) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    let queue = queue.clone();
+    let sysex_queue = sysex_queue.clone();
+    let mut parser = MidiParser::new();
+    move |stamp, message, _| {
+        for midi_message in parser.parse(message) {
+            queue.push(InputEvent {
+                timestamp: stamp,
+                port,
+                message: midi_message,
+                received: Some(Instant::now()),
+            });
+        }
+        for sysex in parser.take_sysex() {
+            sysex_queue.push((port, sysex));
+        }
+    }
+}
+
+// asks the device who it is through its output port with the same name,
+// the reply comes back as sysex and picks the bindings profile
+fn send_device_inquiry(device_name: &str) {
+    let midi_output = match midir::MidiOutput::new("Device inquiry") {
+        Ok(midi_output) => midi_output,
+        Err(_) => return,
+    };
+    let output_port = midi_output
+        .ports()
+        .into_iter()
+        .find(|port| midi_output.port_name(port).ok().as_deref() == Some(device_name));
+    // input only devices can't be asked, port name matching is used for them
+    let output_port = match output_port {
+        Some(output_port) => output_port,
+        None => return,
+    };
+    match midi_output.connect(&output_port, "device inquiry") {
+        Ok(mut connection) => {
+            if let Err(err) = connection.send(&DEVICE_INQUIRY) {
+                println!("can't send device inquiry to {}: {}", device_name, err);
+            }
+        }
+        Err(err) => println!("can't send device inquiry to {}: {}", device_name, err),
+    }
+}
+
+// reads any number of midi devices at once, a pad controller next to a fader box
+// controls of different devices are told apart by the port of their ControlId
+pub struct MidiSource {
+    // in port order, none means the game runs without a controller
+    // the first one is the main device, bindings profiles and feedback follow it
+    devices: Vec<MidiDevice>,
+    // rtp-midi sessions and osc apps, each on a port of its own after the devices added before it
+    network: Vec<NetworkListener>,
+    next_port: u8,
+    // separate instance so ports can be listed while the devices are connected
+    scanner: Option<midir::MidiInput>,
+    rescan_timer: f32,
+
+    // the midi threads only ever push here, the game drains it once per frame
+    queue: EventQueue,
+    // every event drained this frame, in the order they arrived
+    events: Vec<InputEvent>,
+    sysex_queue: SysExQueue,
+    sysex: Vec<(u8, Vec<u8>)>,
+    controls: ControlStates,
+    // live events are written here while recording
+    recorder: Option<Recorder>,
+    // recorded events are added to the live ones while replaying
+    replay: Option<Replay>,
+}
+
+#[derive(Eq, Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    Connected(String),
+    Disconnected(String),
+    // the device answered the device inquiry
+    Identified(String, DeviceIdentity),
+}
+
+impl MidiSource {
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+            network: Vec::new(),
+            next_port: 0,
+            scanner: midir::MidiInput::new("Input scanner").ok(),
+            rescan_timer: 0f32,
+            queue: EventQueue::new(),
+            events: Vec::with_capacity(EVENT_QUEUE_CAPACITY),
+            sysex_queue: EventQueue::with_capacity(SYSEX_QUEUE_CAPACITY),
+            sysex: Vec::new(),
+            controls: ControlStates::new(),
+            recorder: None,
+            replay: None,
+        }
+    }
+
+    // names of every port currently available, for add_port_by_name
+    pub fn port_names(&self) -> Vec<String> {
+        match &self.scanner {
+            Some(scanner) => scanner
+                .ports()
+                .iter()
+                .map(|port| {
+                    scanner
+                        .port_name(port)
+                        .unwrap_or_else(|_| String::from("unknown device"))
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // adds the device even when no port matches yet, so it keeps its port number
+    // and is connected once it's plugged in, false when it isn't there now
+    pub fn add_port_by_name(&mut self, name: &str) -> bool {
+        if self.add_device(name.to_string()).is_none() {
+            return false;
+        }
+        let name = name.to_lowercase();
+        self.port_names()
+            .iter()
+            .any(|port_name| port_name.to_lowercase().contains(&name))
+    }
+
+    // None when every port number is taken
+    fn add_device(&mut self, name: String) -> Option<&mut MidiDevice> {
+        let port = self.free_port()?;
+        self.next_port += 1;
+        self.devices.push(MidiDevice::new(name, port));
+        self.devices.last_mut()
+    }
+
+    // the port number the next device or listener gets, the last one is never handed
+    // out so counting past it can't overflow
+    fn free_port(&self) -> Option<u8> {
+        match self.next_port.checked_add(1) {
+            Some(_) => Some(self.next_port),
+            None => {
+                println!(
+                    "out of midi ports, {} devices and listeners at most",
+                    self.next_port
+                );
+                None
+            }
+        }
+    }
+
+    pub fn has_devices(&self) -> bool {
+        !self.devices.is_empty()
+    }
+
+    pub fn devices(&self) -> &[MidiDevice] {
+        &self.devices
+    }
+
+    // port name and identity of each device in port order, what bindings profiles are picked by
+    pub fn device_identities(&self) -> Vec<(&str, Option<&DeviceIdentity>)> {
+        self.devices
+            .iter()
+            .map(|device| (device.name(), device.identity()))
+            .collect()
+    }
+
+    // whether midi can come in over the network
+    pub fn is_listening(&self) -> bool {
+        !self.network.is_empty()
+    }
+
+    pub fn set_velocity_threshold(&mut self, velocity_threshold: u8) {
+        self.controls.set_velocity_threshold(velocity_threshold);
+    }
+
+    pub fn set_pair_14_bit_cc(&mut self, pair_14_bit_cc: bool) {
+        self.controls.set_pair_14_bit_cc(pair_14_bit_cc);
+    }
+
+    // member channels of the mpe lower zone until the device announces its zones
+    pub fn set_mpe_members(&mut self, members: u8) {
+        self.controls.set_mpe_members(members);
+    }
+
+    // decoded at full resolution, nrpn data entry shows up as the parameter
+    // values are as the device sent them, before calibration or encoders are applied
+    pub fn control_updates(&self) -> &[(ControlId, ControlValue)] {
+        self.controls.updates()
+    }
+
+    pub fn events_this_frame(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    // complete sysex messages drained this frame, from F0 to F7, with their port
+    pub fn sysex_this_frame(&self) -> &[(u8, Vec<u8>)] {
+        &self.sysex
+    }
+
+    // events lost because the queue was full
+    pub fn dropped_events(&self) -> usize {
+        self.queue.dropped()
+    }
+
+    // whether the main device is connected
+    pub fn is_connected(&self) -> bool {
+        self.devices
+            .first()
+            .is_some_and(|device| device.is_connected())
+    }
+
+    pub fn start_recording(&mut self, path: &str) {
+        match Recorder::create(path) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(err) => println!("can't record to {}: {}", path, err),
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // controls start from scratch so the replay plays out the same every time
+    pub fn start_replay(&mut self, path: &str) {
+        match Replay::load(path) {
+            Ok(replay) => {
+                self.replay = Some(replay);
+                self.controls.clear();
+            }
+            Err(err) => println!("can't replay {}: {}", path, err),
+        }
+    }
+
+    pub fn stop_replay(&mut self) {
+        self.replay = None;
+        // buttons held down in the replay would stay held otherwise
+        self.controls.clear();
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    // call every frame, rescans ports now and then to notice devices
+    // being unplugged or plugged back in
+    pub fn poll_devices(&mut self, dt: f32) -> Vec<DeviceEvent> {
+        let mut events = Vec::new();
+        for device in self.devices.iter_mut() {
+            if device.is_identity_new {
+                device.is_identity_new = false;
+                if let Some(identity) = &device.identity {
+                    events.push(DeviceEvent::Identified(
+                        device.name().to_string(),
+                        identity.clone(),
+                    ));
+                }
+            }
+        }
+        self.rescan_timer -= dt;
+        if self.rescan_timer > 0f32 || self.devices.is_empty() {
+            return events;
+        }
+        self.rescan_timer = RESCAN_INTERVAL;
+
+        let port_names = self.port_names();
+        for device in self.devices.iter_mut().filter(|device| !device.is_virtual) {
+            let is_present = match &device.port_name {
+                // the same port it was on, another one matching the name would be another device
+                Some(port_name) if device.is_connected() => port_names.contains(port_name),
+                _ => port_names.iter().any(|port_name| device.matches(port_name)),
+            };
+            if device.is_connected() && !is_present {
+                device.disconnect();
+                // held buttons would otherwise stay held forever
+                self.controls.clear_port(device.port);
+                events.push(DeviceEvent::Disconnected(device.name().to_string()));
+            } else if !device.is_connected() && is_present {
+                device.connect(&port_names, &self.queue, &self.sysex_queue);
+                if device.is_connected() {
+                    events.push(DeviceEvent::Connected(device.name().to_string()));
+                }
+            }
+        }
+        events
+    }
+
+    // connects every added device that is plugged in, the game keeps running
+    // without the others and they're connected once they show up
+    pub fn connect(&mut self) {
+        let port_names = self.port_names();
+        for device in self.devices.iter_mut() {
+            device.connect(&port_names, &self.queue, &self.sysex_queue);
+        }
+    }
+
+    // makes a port other software can connect to and play the game with,
+    // like a daw, a sequencer or a test script
+    #[cfg(unix)]
+    pub fn connect_virtual(&mut self) {
+        use midir::os::unix::VirtualInput;
+        let callback = on_message(self.next_port, &self.queue, &self.sysex_queue);
+        let device = match self.add_device(String::from(VIRTUAL_INPUT_PORT)) {
+            Some(device) => device,
+            None => return,
+        };
+        device.is_virtual = true;
+        let midi_input = match device.midi_input.take() {
+            Some(midi_input) => midi_input,
+            None => return,
+        };
+        match midi_input.create_virtual(VIRTUAL_INPUT_PORT, callback, ()) {
+            Ok(connection) => device.connection = Some(connection),
+            Err(err) => {
+                println!("can't create virtual midi port: {}", err);
+                device.midi_input = Some(err.into_inner());
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect_virtual(&mut self) {
+        println!("virtual midi ports aren't supported on this platform");
+    }
+
+    // accepts rtp-midi sessions on the udp port and the one after it, from network midi
+    // on macOS and iOS, rtpMIDI on windows or the midi_send tool
+    pub fn listen_rtp_midi(&mut self, udp_port: u16) {
+        let port = match self.free_port() {
+            Some(port) => port,
+            None => return,
+        };
+        let mut callback = on_message(port, &self.queue, &self.sysex_queue);
+        match rtp_midi::listen(udp_port, move |stamp, bytes| {
+            callback(stamp, bytes, &mut ())
+        }) {
+            Ok(listener) => {
+                println!("listening for rtp-midi on {}", listener.address());
+                self.network.push(listener);
+                self.next_port += 1;
+            }
+            Err(err) => println!("can't listen for rtp-midi on port {}: {}", udp_port, err),
+        }
+    }
+
+    // reads osc from phone and tablet controller apps, see osc::listen for the addresses
+    pub fn listen_osc(&mut self, udp_port: u16) {
+        let port = match self.free_port() {
+            Some(port) => port,
+            None => return,
+        };
+        let mut callback = on_message(port, &self.queue, &self.sysex_queue);
+        match osc::listen(udp_port, move |stamp, bytes| {
+            callback(stamp, bytes, &mut ())
+        }) {
+            Ok(listener) => {
+                println!("listening for osc on {}", listener.address());
+                self.network.push(listener);
+                self.next_port += 1;
+            }
+            Err(err) => println!("can't listen for osc on port {}: {}", udp_port, err),
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        for device in self.devices.iter_mut() {
+            device.disconnect();
+        }
+        // held buttons would otherwise stay held forever
+        self.controls.clear();
+    }
+}
+
+impl InputSource for MidiSource {
+    fn is_button_held(&self, id: ControlId) -> bool {
+        self.controls.get(id).is_some_and(|state| state.is_held())
+    }
+
+    fn is_button_pressed(&self, id: ControlId) -> bool {
+        self.controls
+            .get(id)
+            .is_some_and(|state| state.is_pressed())
+    }
+
+    fn is_button_released(&self, id: ControlId) -> bool {
+        self.controls
+            .get(id)
+            .is_some_and(|state| state.is_released())
+    }
+
+    fn button_velocity(&self, id: ControlId) -> Option<f32> {
+        self.controls.get(id).map(|state| state.velocity())
+    }
+
+    fn fraction(&self, id: ControlId) -> Option<f32> {
+        self.controls.get(id).and_then(|state| state.fraction())
+    }
+
+    fn fraction_reached_limit(
+        &self,
+        id: ControlId,
+        fraction: f32,
+        limit: SliderLimitCheck,
+    ) -> bool {
+        self.controls
+            .get(id)
+            .is_some_and(|state| state.reached_limit(fraction, limit))
+    }
+
+    // every step is seen here, so the hysteresis is tracked as values arrive
+    fn trigger_reached(&self, id: ControlId, _trigger: &Trigger, limit: SliderLimitCheck) -> bool {
+        self.controls
+            .get(id)
+            .is_some_and(|state| state.reached_trigger(limit))
+    }
+
+    fn note_expression(&self, id: ControlId) -> Option<NoteExpression> {
+        self.controls.expression().note(id)
+    }
+
+    // relative encoders are accumulated and absolute controls are calibrated and shaped
+    fn set_bindings(&mut self, bindings: &Bindings) {
+        self.controls.set_bindings(bindings);
+    }
+
+    // drain everything that arrived since last frame
+    fn update(&mut self, _dt: f32) {
+        self.events.clear();
+        self.queue.drain_into(&mut self.events);
+        self.sysex.clear();
+        self.sysex_queue.drain_into(&mut self.sysex);
+        for (port, sysex) in self.sysex.iter() {
+            let identity = DeviceIdentity::from_reply(sysex);
+            let device = self.devices.iter_mut().find(|device| device.port == *port);
+            if let (Some(identity), Some(device)) = (identity, device) {
+                device.identity = Some(identity);
+                device.is_identity_new = true;
+            }
+        }
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record_frame(&self.events) {
+                println!("recording stopped: {}", err);
+                self.recorder = None;
+            }
+        }
+        // the last frame of a replay is played first, what it left held is let go after
+        if self.replay.as_ref().is_some_and(Replay::is_finished) {
+            self.stop_replay();
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.next_frame(&mut self.events);
+        }
+        self.controls.begin_frame();
+        for event in self.events.iter() {
+            self.controls.apply(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn replay_cut_off_mid_press_lets_go_when_it_ends() {
+        let path = std::env::temp_dir().join("midi_game_cut_off.midilog");
+        // the pad goes down on the first frame and the recording stops before it's let go
+        std::fs::write(&path, "0 0 0 99 29 7f\n").unwrap();
+        let mut midi = MidiSource::new();
+        midi.start_replay(path.to_str().unwrap());
+        let pad = ControlId::note(9, 41);
+        midi.update(0f32);
+        assert!(midi.is_button_pressed(pad));
+        assert!(midi.is_replaying());
+        midi.update(0f32);
+        assert!(!midi.is_replaying());
+        assert!(!midi.is_button_held(pad));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // plays the game through the virtual port like a daw would
+    #[test]
+    #[ignore = "needs a midi backend, like the alsa sequencer"]
+    #[cfg(target_os = "linux")]
+    fn software_sender_plays_through_the_virtual_port() {
+        let mut midi = MidiSource::new();
+        midi.connect_virtual();
+        assert!(midi.is_connected());
+
+        let midi_output = midir::MidiOutput::new("test sender").unwrap();
+        let port = midi_output
+            .ports()
+            .into_iter()
+            .find(|port| {
+                midi_output
+                    .port_name(port)
+                    .unwrap()
+                    .contains(VIRTUAL_INPUT_PORT)
+            })
+            .expect("virtual port not found");
+        let mut sender = midi_output.connect(&port, "test sender").unwrap();
+        sender.send(&[0x90, 64, 100]).unwrap();
+        sender.send(&[0xB0, 1, 127]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        midi.update(0f32);
+        assert!(midi.is_button_pressed(ControlId::note(0, 64)));
+        assert_eq!(midi.fraction(ControlId::cc(0, 1)), Some(1f32));
+    }
+}
@@ -0,0 +1,67 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// how often the socket threads check if they should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// one ethernet frame, rtp-midi and osc packets from controllers are far smaller
+const MAX_PACKET_LEN: usize = 1500;
+
+// midi coming in over the network, the sockets are read on threads of their own
+// that stop when this is dropped
+pub struct NetworkListener {
+    address: SocketAddr,
+    is_running: Arc<AtomicBool>,
+}
+
+impl NetworkListener {
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            is_running: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    // where it listens, the first socket for rtp-midi
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // calls on_packet with every packet the socket gets and who sent it,
+    // answers go back through the socket
+    pub fn spawn(
+        &self,
+        socket: UdpSocket,
+        mut on_packet: impl FnMut(&UdpSocket, &[u8], SocketAddr) + Send + 'static,
+    ) -> io::Result<()> {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let is_running = self.is_running.clone();
+        thread::spawn(move || {
+            let mut buffer = [0u8; MAX_PACKET_LEN];
+            while is_running.load(Ordering::Relaxed) {
+                match socket.recv_from(&mut buffer) {
+                    Ok((len, from)) => on_packet(&socket, &buffer[..len], from),
+                    Err(err) => match err.kind() {
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {}
+                        // windows reports an earlier answer that couldn't be delivered here
+                        io::ErrorKind::ConnectionReset => {}
+                        _ => {
+                            println!("stopped listening on {:?}: {}", socket.local_addr(), err);
+                            break;
+                        }
+                    },
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for NetworkListener {
+    fn drop(&mut self) {
+        self.is_running.store(false, Ordering::Relaxed);
+    }
+}
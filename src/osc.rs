@@ -0,0 +1,238 @@
+use crate::midi_message::*;
+use crate::network_midi::*;
+use std::io;
+use std::net::UdpSocket;
+use std::time::Instant;
+
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+// after the tag, when the bundle should happen, it's played as soon as it arrives
+const TIME_TAG_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OscArgument {
+    Int(i32),
+    Float(f32),
+    // osc midi type, port id, status and two data bytes
+    Midi([u8; 4]),
+    // strings, blobs and the like, nothing is sent as those here
+    Other,
+}
+
+impl OscArgument {
+    // ints are midi values already, floats go from 0 to 1 like phone apps send
+    fn to_data_byte(self) -> Option<u8> {
+        match self {
+            OscArgument::Int(value) => Some(value.clamp(0, 127) as u8),
+            OscArgument::Float(value) => Some((value.clamp(0f32, 1f32) * 127f32).round() as u8),
+            _ => None,
+        }
+    }
+}
+
+// listens for osc from controller apps that speak osc instead of midi, every message
+// is turned into the midi it stands for:
+//   /note note velocity [channel]
+//   /cc controller value [channel]
+//   /pressure value [channel]
+//   /pitchbend value [channel]    ints from 0 to 16383, floats from 0 to 1
+//   /midi m                       the osc midi type, as it is
+// channels count from 1 like on devices, the first one when it's left out
+pub fn listen(
+    port: u16,
+    mut on_midi: impl FnMut(u64, &[u8]) + Send + 'static,
+) -> io::Result<NetworkListener> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    let listener = NetworkListener::new(socket.local_addr()?);
+    let start = Instant::now();
+    listener.spawn(socket, move |_, packet, _| {
+        let mut bytes = Vec::new();
+        decode_packet(packet, &mut bytes);
+        if !bytes.is_empty() {
+            on_midi(start.elapsed().as_micros() as u64, &bytes);
+        }
+    })?;
+    Ok(listener)
+}
+
+// midi of a message or of everything in a bundle, added to bytes,
+// anything that can't be read is skipped
+fn decode_packet(packet: &[u8], bytes: &mut Vec<u8>) {
+    if !packet.starts_with(BUNDLE_TAG) {
+        if let Some((address, arguments)) = read_message(packet) {
+            if let Some(midi) = to_midi(&address, &arguments) {
+                bytes.extend_from_slice(&midi);
+            }
+        }
+        return;
+    }
+    let mut i = BUNDLE_TAG.len() + TIME_TAG_LEN;
+    while let Some(size) = packet.get(i..i + 4) {
+        let size = i32::from_be_bytes([size[0], size[1], size[2], size[3]]).max(0) as usize;
+        let element = match packet.get(i + 4..i + 4 + size) {
+            Some(element) => element,
+            None => return,
+        };
+        decode_packet(element, bytes);
+        i += 4 + size;
+    }
+}
+
+// a string ends with at least one zero and is padded to a multiple of 4 bytes
+fn read_string(packet: &[u8], i: &mut usize) -> Option<String> {
+    let len = packet.get(*i..)?.iter().position(|byte| *byte == 0)?;
+    let string = String::from_utf8(packet[*i..*i + len].to_vec()).ok()?;
+    *i += (len + 4) / 4 * 4;
+    Some(string)
+}
+
+fn read_word(packet: &[u8], i: &mut usize) -> Option<[u8; 4]> {
+    let word = packet.get(*i..*i + 4)?;
+    *i += 4;
+    Some([word[0], word[1], word[2], word[3]])
+}
+
+fn read_message(packet: &[u8]) -> Option<(String, Vec<OscArgument>)> {
+    let mut i = 0;
+    let address = read_string(packet, &mut i)?;
+    let type_tags = read_string(packet, &mut i)?;
+    let mut arguments = Vec::new();
+    for tag in type_tags.strip_prefix(',')?.chars() {
+        let argument = match tag {
+            'i' => OscArgument::Int(i32::from_be_bytes(read_word(packet, &mut i)?)),
+            'f' => OscArgument::Float(f32::from_be_bytes(read_word(packet, &mut i)?)),
+            'm' => OscArgument::Midi(read_word(packet, &mut i)?),
+            's' | 'S' => {
+                read_string(packet, &mut i)?;
+                OscArgument::Other
+            }
+            'b' => {
+                let len = i32::from_be_bytes(read_word(packet, &mut i)?).max(0) as usize;
+                i += len + (4 - len % 4) % 4;
+                OscArgument::Other
+            }
+            'h' | 'd' | 't' => {
+                read_word(packet, &mut i)?;
+                read_word(packet, &mut i)?;
+                OscArgument::Other
+            }
+            'c' | 'r' => {
+                read_word(packet, &mut i)?;
+                OscArgument::Other
+            }
+            // true, false, nil and impulse have no data, arrays aren't supported
+            'T' | 'F' | 'N' | 'I' => OscArgument::Other,
+            _ => return None,
+        };
+        arguments.push(argument);
+    }
+    Some((address, arguments))
+}
+
+fn to_midi(address: &str, arguments: &[OscArgument]) -> Option<Vec<u8>> {
+    let data = |index: usize| {
+        arguments
+            .get(index)
+            .and_then(|argument| argument.to_data_byte())
+    };
+    let channel = |index: usize| match arguments.get(index) {
+        Some(OscArgument::Int(channel)) => channel.saturating_sub(1).clamp(0, 15) as u8,
+        _ => 0,
+    };
+    let midi = match address {
+        "/note" => vec![0x90 | channel(2), data(0)?, data(1)?],
+        "/cc" => vec![0xB0 | channel(2), data(0)?, data(1)?],
+        "/pressure" => vec![0xD0 | channel(1), data(0)?],
+        "/pitchbend" => {
+            let value = match arguments.first()? {
+                OscArgument::Int(value) => (*value).clamp(0, 16383) as u16,
+                OscArgument::Float(value) => (value.clamp(0f32, 1f32) * 16383f32).round() as u16,
+                _ => return None,
+            };
+            vec![0xE0 | channel(1), (value & 0x7F) as u8, (value >> 7) as u8]
+        }
+        "/midi" => match arguments.first()? {
+            OscArgument::Midi([_port, status, data_1, data_2]) => {
+                let midi = [*status, *data_1, *data_2];
+                // only channel messages, a lone data byte would be read with running status
+                match status {
+                    0x80..=0xEF => midi[..1 + data_len(*status)].to_vec(),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(midi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn message(address: &str, type_tags: &str, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        for string in [address, type_tags].iter() {
+            packet.extend_from_slice(string.as_bytes());
+            packet.extend(vec![0; 4 - string.len() % 4]);
+        }
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn bundle_of_notes_and_floats() {
+        let mut note = 64i32.to_be_bytes().to_vec();
+        note.extend_from_slice(&1f32.to_be_bytes());
+        note.extend_from_slice(&2i32.to_be_bytes());
+        let note = message("/note", ",ifi", &note);
+        let mut cc = 7i32.to_be_bytes().to_vec();
+        cc.extend_from_slice(&0.5f32.to_be_bytes());
+        let cc = message("/cc", ",if", &cc);
+
+        let mut bundle = BUNDLE_TAG.to_vec();
+        bundle.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        for element in [note, cc].iter() {
+            bundle.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            bundle.extend_from_slice(element);
+        }
+        let mut bytes = Vec::new();
+        decode_packet(&bundle, &mut bytes);
+        assert_eq!(bytes, vec![0x91, 64, 127, 0xB0, 7, 64]);
+
+        // unknown addresses and missing arguments are skipped
+        let mut bytes = Vec::new();
+        decode_packet(&message("/fader", ",f", &1f32.to_be_bytes()), &mut bytes);
+        decode_packet(&message("/note", ",i", &64i32.to_be_bytes()), &mut bytes);
+        decode_packet(&message("/midi", ",m", &[0, 0xC3, 5, 0]), &mut bytes);
+        assert_eq!(bytes, vec![0xC3, 5]);
+
+        // out of range channels are clamped
+        let mut pressure = 100i32.to_be_bytes().to_vec();
+        pressure.extend_from_slice(&i32::MIN.to_be_bytes());
+        let mut bytes = Vec::new();
+        decode_packet(&message("/pressure", ",ii", &pressure), &mut bytes);
+        assert_eq!(bytes, vec![0xD0, 100]);
+    }
+
+    // what the midi_send tool does against a running game
+    #[test]
+    fn listens_on_localhost() {
+        let (sender, receiver) = mpsc::channel();
+        let listener = listen(0, move |_, bytes| sender.send(bytes.to_vec()).unwrap()).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = ("127.0.0.1", listener.address().port());
+        socket
+            .send_to(
+                &message("/pitchbend", ",i", &8192i32.to_be_bytes()),
+                address,
+            )
+            .unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(1)),
+            Ok(vec![0xE0, 0, 64])
+        );
+    }
+}
@@ -0,0 +1,88 @@
+// a fader this close to the held value counts as having caught it, about one step
+const PICKUP_TOLERANCE: f32 = 1f32 / 127f32;
+
+// soft takeover for one action, the game keeps its own value until the fader
+// is moved across it so touching a fader never makes the value jump
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pickup {
+    // what the game reads, follows the fader once caught
+    value: f32,
+    // where the fader was last frame, None until it's seen
+    fader: Option<f32>,
+    is_caught: bool,
+}
+
+impl Pickup {
+    pub fn new(value: f32) -> Self {
+        Self {
+            value,
+            fader: None,
+            is_caught: false,
+        }
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    // None once caught or before the fader is seen
+    pub fn fader(&self) -> Option<f32> {
+        match self.is_caught {
+            true => None,
+            false => self.fader,
+        }
+    }
+
+    pub fn is_caught(&self) -> bool {
+        self.is_caught
+    }
+
+    // call once per frame with the current fader position
+    pub fn update(&mut self, fader: Option<f32>) {
+        let fader = match fader {
+            Some(fader) => fader,
+            None => return,
+        };
+        if !self.is_caught {
+            // crossing is checked against last frame so a fast sweep past the value still counts
+            let crossed = match self.fader {
+                Some(previous) => (previous - self.value) * (fader - self.value) <= 0f32,
+                None => false,
+            };
+            self.is_caught = crossed || (fader - self.value).abs() <= PICKUP_TOLERANCE;
+        }
+        if self.is_caught {
+            self.value = fader;
+        }
+        self.fader = Some(fader);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_value_until_the_fader_crosses_it() {
+        let mut pickup = Pickup::new(0.5f32);
+        pickup.update(Some(0.9f32));
+        assert_eq!(pickup.value(), 0.5f32);
+        pickup.update(Some(0.6f32));
+        assert_eq!(pickup.value(), 0.5f32);
+        assert_eq!(pickup.fader(), Some(0.6f32));
+        pickup.update(Some(0.2f32));
+        assert!(pickup.is_caught());
+        assert_eq!(pickup.value(), 0.2f32);
+        pickup.update(Some(0.8f32));
+        assert_eq!(pickup.value(), 0.8f32);
+    }
+
+    #[test]
+    fn first_value_on_the_held_value_is_caught() {
+        let mut pickup = Pickup::new(0.5f32);
+        pickup.update(None);
+        assert!(!pickup.is_caught());
+        pickup.update(Some(64f32 / 127f32));
+        assert!(pickup.is_caught());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::bindings::*;
 use crate::constants::*;
 use crate::gun::*;
 use crate::input::*;
@@ -61,10 +62,14 @@ pub struct Player {
     pub pos: Vec2,
     pub y_vel: f32,
     pub jump_strength: f32,
+    // how hard the jump pad was hit, softer hits jump lower
+    pub jump_velocity: f32,
     pub is_grounded: bool,
     pub gun: Gun,
     pub is_crouching: bool,
     pub is_facing_right: bool,
+    // 1 plays the animations at their own frame rate
+    pub animation_speed: f32,
 }
 
 impl Player {
@@ -116,10 +121,12 @@ impl Player {
             pos,
             y_vel: 0f32,
             jump_strength: 0.05f32,
+            jump_velocity: 1f32,
             is_grounded: false,
             gun: Gun::new(),
             is_crouching: false,
             is_facing_right: false,
+            animation_speed: 1f32,
         }
     }
 
@@ -135,12 +142,13 @@ impl Player {
         } else {
             self.is_grounded = false;
         }
-        self.animation.update(dt);
+        self.animation.update(dt * self.animation_speed);
     }
 
     pub fn jump(&mut self) {
         if self.is_grounded {
-            self.y_vel = -self.jump_strength * MAX_JUMP_STRENGTH;
+            let velocity_scale = 0.5f32 + 0.5f32 * self.jump_velocity;
+            self.y_vel = -self.jump_strength * velocity_scale * MAX_JUMP_STRENGTH;
         }
     }
 
@@ -150,31 +158,48 @@ impl Player {
 
     pub fn draw(&mut self) {
         self.animation.draw(&self.pos, !self.is_facing_right);
+        // where a loaded gun points
+        if self.gun.is_loaded() {
+            let facing = if self.is_facing_right { 1f32 } else { -1f32 };
+            let from = self.pos + vec2(5f32, 6.5f32);
+            let to = from + vec2(facing * self.gun.aim().cos(), -self.gun.aim().sin()) * 12f32;
+            draw_line(
+                from.x,
+                from.y,
+                to.x,
+                to.y,
+                1f32,
+                Color::new(0f32, 0f32, 0f32, 0.4f32),
+            );
+        }
     }
 
     pub fn process_input(&mut self, dt: f32, shared_data: &mut SharedData) {
         let mut next_player_anim_optional = None;
-        if shared_data.input.is_button_held(41) {
-            self.pos.x -= MOVE_SPEED * dt;
+        if shared_data.input.action_held(GameAction::MoveLeft) {
+            self.pos.x -= run_speed(&shared_data.input, GameAction::MoveLeft) * dt;
             self.is_facing_right = false;
             next_player_anim_optional = Some(PlayerAnimationNoStanceIdentifier::Run);
-        } else if shared_data.input.is_button_held(45) {
+        } else if shared_data.input.action_held(GameAction::MoveRight) {
             self.is_facing_right = true;
-            self.pos.x += MOVE_SPEED * dt;
+            self.pos.x += run_speed(&shared_data.input, GameAction::MoveRight) * dt;
             next_player_anim_optional = Some(PlayerAnimationNoStanceIdentifier::Run);
         }
 
-        if shared_data.input.is_button_held(64) {
+        if shared_data.input.action_held(GameAction::Jump) {
             self.jump();
         }
 
-        if shared_data.input.is_button_pressed(64) {
+        if shared_data.input.action_pressed(GameAction::Jump) {
+            self.jump_velocity = shared_data.input.action_velocity(GameAction::Jump);
             self.jump();
             play_sound_once(shared_data.sound_resources_optional.as_ref().unwrap().jump);
         }
 
+        self.gun.set_aim(aim_angle(&shared_data.input));
+
         // try shot gun
-        if shared_data.input.is_button_pressed(65) {
+        if shared_data.input.action_pressed(GameAction::Shoot) {
             let result = self.gun.try_consume();
             match result {
                 Ok(()) => {
@@ -208,7 +233,7 @@ impl Player {
 
         if shared_data
             .input
-            .fraction_reached_limit(1, 0.7, SliderLimitCheck::Higher)
+            .action_triggered(GameAction::Latch, SliderLimitCheck::Higher)
         {
             self.gun.set_latch_state(LatchState::Open);
             next_player_anim_optional = Some(PlayerAnimationNoStanceIdentifier::HatchOpen);
@@ -222,7 +247,7 @@ impl Player {
         }
         if shared_data
             .input
-            .fraction_reached_limit(1, 0.3, SliderLimitCheck::Lower)
+            .action_triggered(GameAction::Latch, SliderLimitCheck::Lower)
         {
             self.gun.set_latch_state(LatchState::Closed);
             next_player_anim_optional = Some(PlayerAnimationNoStanceIdentifier::HatchClose);
@@ -237,7 +262,7 @@ impl Player {
 
         if shared_data
             .input
-            .fraction_reached_limit(2, 0.7, SliderLimitCheck::Higher)
+            .action_triggered(GameAction::Crouch, SliderLimitCheck::Higher)
         {
             self.is_crouching = false;
             next_player_anim_optional = Some(PlayerAnimationNoStanceIdentifier::Idle);
@@ -251,7 +276,7 @@ impl Player {
         }
         if shared_data
             .input
-            .fraction_reached_limit(2, 0.3, SliderLimitCheck::Lower)
+            .action_triggered(GameAction::Crouch, SliderLimitCheck::Lower)
         {
             self.is_crouching = true;
             next_player_anim_optional = Some(PlayerAnimationNoStanceIdentifier::Idle);
@@ -264,7 +289,7 @@ impl Player {
             );
         }
 
-        self.set_jump_strength(shared_data.input.get_fraction(0));
+        self.set_jump_strength(shared_data.input.action_fraction(GameAction::JumpStrength));
 
         if let Some(wanted_anim_no_stance) = next_player_anim_optional {
             let wanted_anim = wanted_anim_no_stance.to_animation(self.is_crouching);
@@ -277,3 +302,21 @@ impl Player {
         }
     }
 }
+
+// leaning into a key with aftertouch or on an mpe controller runs faster
+fn run_speed(input: &Input, action: GameAction) -> f32 {
+    let pressure = input
+        .action_expression(action)
+        .map_or(0f32, |expression| expression.pressure);
+    MOVE_SPEED * (1f32 + pressure * PRESSURE_RUN_BOOST)
+}
+
+// bending or sliding along the held shoot note aims the gun, it's level again once let go
+fn aim_angle(input: &Input) -> f32 {
+    let aim = input
+        .action_expression(GameAction::Shoot)
+        .map_or(0f32, |expression| {
+            expression.pitch_bend / AIM_BEND_RANGE + (expression.timbre - 0.5f32) * 2f32
+        });
+    aim.clamp(-1f32, 1f32) * MAX_AIM_ANGLE
+}
@@ -0,0 +1,142 @@
+use crate::event_queue::*;
+use crate::midi_message::*;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+pub const RECORDING_PATH: &str = "recording.midilog";
+
+// one event per line, written as it arrives so nothing is lost if the game is closed:
+//   frame timestamp port status data...
+// frame counts from the start of the recording, timestamp is from midir in microseconds
+// and the message bytes are hex
+const HEADER: &str = "# midi_game recording: frame timestamp port bytes";
+
+fn format_line(frame: u64, event: &InputEvent) -> String {
+    let mut line = format!("{} {} {}", frame, event.timestamp, event.port);
+    for byte in event.message.to_bytes() {
+        line.push_str(&format!(" {:02x}", byte));
+    }
+    line
+}
+
+fn parse_line(line: &str) -> Option<(u64, u64, u8, Vec<u8>)> {
+    let mut fields = line.split_whitespace();
+    let frame = fields.next()?.parse().ok()?;
+    let timestamp = fields.next()?.parse().ok()?;
+    let port = fields.next()?.parse().ok()?;
+    let bytes = fields
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((frame, timestamp, port, bytes))
+}
+
+// writes every event the midi device sends to a file
+pub struct Recorder {
+    writer: BufWriter<File>,
+    frame: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", HEADER)?;
+        Ok(Self { writer, frame: 0 })
+    }
+
+    // call once per frame, even when nothing happened, so frames line up on replay
+    pub fn record_frame(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        for event in events {
+            writeln!(self.writer, "{}", format_line(self.frame, event))?;
+        }
+        if !events.is_empty() {
+            self.writer.flush()?;
+        }
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+// plays a recording back, every event lands on the same frame it was recorded on
+pub struct Replay {
+    // sorted by frame
+    events: Vec<(u64, InputEvent)>,
+    next: usize,
+    frame: u64,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut events = Vec::new();
+        let mut parser = MidiParser::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (frame, timestamp, port, bytes) = match parse_line(line) {
+                Some(fields) => fields,
+                None => {
+                    println!("{}:{} isn't a recorded event, skipped", path, number + 1);
+                    continue;
+                }
+            };
+            for message in parser.parse(&bytes) {
+                let event = InputEvent {
+                    timestamp,
+                    port,
+                    message,
+                    received: None,
+                };
+                events.push((frame, event));
+            }
+        }
+        events.sort_by_key(|(frame, _)| *frame);
+        Ok(Self {
+            events,
+            next: 0,
+            frame: 0,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.events.len()
+    }
+
+    // call once per frame, adds the events recorded on this frame
+    pub fn next_frame(&mut self, events: &mut Vec<InputEvent>) {
+        while let Some((frame, event)) = self.events.get(self.next) {
+            if *frame > self.frame {
+                break;
+            }
+            events.push(*event);
+            self.next += 1;
+        }
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_round_trip() {
+        let event = InputEvent {
+            timestamp: 123456,
+            port: 1,
+            message: MidiMessage::PitchBend {
+                channel: 3,
+                value: 8193,
+            },
+            received: None,
+        };
+        let line = format_line(42, &event);
+        assert_eq!(line, "42 123456 1 e3 01 40");
+        let (frame, timestamp, port, bytes) = parse_line(&line).unwrap();
+        assert_eq!((frame, timestamp, port), (42, 123456, 1));
+        assert_eq!(MidiParser::new().parse(&bytes), vec![event.message]);
+        assert_eq!(parse_line("42 123456 1 zz"), None);
+    }
+}
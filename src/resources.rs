@@ -1,4 +1,11 @@
+use crate::beat_clock::*;
+use crate::bindings::*;
+use crate::config::*;
 use crate::input::*;
+use crate::latency::*;
+use crate::midi_feedback::*;
+use crate::midi_monitor::*;
+use crate::midi_source::*;
 use macroquad::audio::*;
 use macroquad::prelude::*;
 use macroquad_tantan_toolbox::resources::*;
@@ -19,6 +26,77 @@ pub struct SharedData {
     pub raw_image_resources_optional: Option<RawImageResources>,
     pub sound_resources_optional: Option<SoundResources>,
     pub input: Input,
+    pub feedback: MidiFeedback,
+    // only when --latency-diagnostics is on
+    pub latency: Option<LatencyMonitor>,
+    pub monitor: MidiMonitor,
+    // follows midi clock and transport from a sequencer
+    pub clock: BeatClock,
+    pub config: Config,
+    pub bindings_file: BindingsFile,
+    // device changes that happened this frame
+    pub device_events: Vec<DeviceEvent>,
+}
+
+impl SharedData {
+    pub fn poll_devices(&mut self, dt: f32) {
+        self.device_events = self.input.midi.poll_devices(dt);
+        // disconnected devices are shown on screen, identities are printed so they
+        // can be copied into a bindings profile
+        for event in self.device_events.iter() {
+            if let DeviceEvent::Identified(device_name, identity) = event {
+                println!("{} identified as {}", device_name, identity);
+            }
+        }
+        if !self.device_events.is_empty() {
+            self.apply_bindings_profile();
+        }
+    }
+
+    // picks the bindings profile matching the connected devices, by their identity once
+    // they answered the device inquiry and by their port name until then
+    pub fn apply_bindings_profile(&mut self) {
+        let bindings = self
+            .bindings_file
+            .bindings_for(&self.input.midi.device_identities())
+            .clone();
+        self.feedback.set_feedback(&bindings.feedback);
+        self.input.set_bindings(bindings);
+        self.connect_feedback();
+    }
+
+    // profile of the connected devices, the bindings screen saves its changes here
+    pub fn device_bindings_mut(&mut self) -> &mut Bindings {
+        self.bindings_file
+            .bindings_for_mut(&self.input.midi.device_identities())
+    }
+
+    // feedback follows the main midi device unless another output or the virtual port is configured
+    fn connect_feedback(&mut self) {
+        // the virtual port is made once on startup
+        if self.config.virtual_feedback_port {
+            return;
+        }
+        let device_name = match &self.config.feedback_device {
+            Some(device_name) => Some(device_name.as_str()),
+            // the virtual input has no output of its own, connecting by its name
+            // would send the feedback straight back into the game
+            None => self
+                .input
+                .midi
+                .devices()
+                .first()
+                .filter(|device| !device.is_virtual())
+                .map(|device| device.name()),
+        };
+        match device_name {
+            Some(device_name) if self.input.midi.is_connected() => {
+                let device_name = device_name.to_string();
+                self.feedback.connect(&device_name);
+            }
+            _ => self.feedback.disconnect(),
+        }
+    }
 }
 
 #[derive(Hash, Eq, Clone, Debug, Copy, PartialEq)]
@@ -38,8 +116,7 @@ pub struct TextureResources {
 }
 
 pub struct RawImageResources {
-    // optional so we can consume it
-    pub water_normal: Option<Image>,
+    pub water_normal: Image,
 }
 
 #[derive(Hash, Eq, Clone, Debug, Copy, PartialEq)]
@@ -94,7 +171,7 @@ impl Resources<RawImageResourceIdentifier, Image, DefaultFactory> for RawImageRe
         builder: &mut ResourceBuilder<RawImageResourceIdentifier, Self, Image, DefaultFactory>,
     ) -> Self {
         Self {
-            water_normal: Some(builder.get_or_panic(RawImageResourceIdentifier::WaterNormal)),
+            water_normal: builder.get_or_panic(RawImageResourceIdentifier::WaterNormal),
         }
     }
 }
@@ -0,0 +1,203 @@
+use crate::midi_message::*;
+use crate::network_midi::*;
+use std::io;
+use std::net::UdpSocket;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// how the game shows up in the session list of the sender
+pub const SESSION_NAME: &str = "midi_game";
+
+// applemidi session commands start with this signature and two letters
+const SIGNATURE: [u8; 2] = [0xFF, 0xFF];
+const INVITATION: [u8; 2] = *b"IN";
+const INVITATION_ACCEPTED: [u8; 2] = *b"OK";
+const SYNC: [u8; 2] = *b"CK";
+const PROTOCOL_VERSION: u32 = 2;
+// sync packets, signature, command, ssrc, count, padding and three 64 bit timestamps
+const SYNC_LEN: usize = 36;
+const RTP_HEADER_LEN: usize = 12;
+const RTP_MIDI_PAYLOAD_TYPE: u8 = 0x61;
+
+// reads rtp-midi (applemidi) sessions, like network midi on macOS and iOS or rtpMIDI on windows,
+// accepting every invitation, events go to on_midi as raw bytes with a timestamp in microseconds
+pub fn listen(
+    control_port: u16,
+    mut on_midi: impl FnMut(u64, &[u8]) + Send + 'static,
+) -> io::Result<NetworkListener> {
+    let control = UdpSocket::bind(("0.0.0.0", control_port))?;
+    let address = control.local_addr()?;
+    // the data port is always the one after the control port
+    let data_port = address.port().checked_add(1).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the rtp midi control port can't be the last port",
+        )
+    })?;
+    let data = UdpSocket::bind(("0.0.0.0", data_port))?;
+    let listener = NetworkListener::new(address);
+    let start = Instant::now();
+    // any number nobody else is likely to pick
+    let ssrc = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos());
+
+    listener.spawn(control, move |socket, packet, from| {
+        if let Some(reply) = session_reply(packet, ssrc, start) {
+            let _ = socket.send_to(&reply, from);
+        }
+    })?;
+    // midi with phantom status leaves out the status byte it had in the last packet
+    let mut running_status = None;
+    listener.spawn(data, move |socket, packet, from| {
+        if let Some(reply) = session_reply(packet, ssrc, start) {
+            let _ = socket.send_to(&reply, from);
+        } else if let Some(bytes) = midi_commands(packet, &mut running_status) {
+            on_midi(start.elapsed().as_micros() as u64, &bytes);
+        }
+    })?;
+    Ok(listener)
+}
+
+// what to answer an applemidi session command with, None when it needs no answer
+// or isn't one, ending a session or receiver feedback need nothing
+fn session_reply(packet: &[u8], ssrc: u32, start: Instant) -> Option<Vec<u8>> {
+    if packet.get(..2)? != SIGNATURE {
+        return None;
+    }
+    match [*packet.get(2)?, *packet.get(3)?] {
+        INVITATION => {
+            let token = packet.get(8..12)?;
+            let mut reply = SIGNATURE.to_vec();
+            reply.extend_from_slice(&INVITATION_ACCEPTED);
+            reply.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            reply.extend_from_slice(token);
+            reply.extend_from_slice(&ssrc.to_be_bytes());
+            reply.extend_from_slice(SESSION_NAME.as_bytes());
+            reply.push(0);
+            Some(reply)
+        }
+        // the sender starts with count 0, answering with our time in 100 microsecond
+        // ticks lets it work out the latency, it finishes with count 2 on its own
+        SYNC if packet.len() >= SYNC_LEN && packet[8] == 0 => {
+            let mut reply = packet[..SYNC_LEN].to_vec();
+            reply[4..8].copy_from_slice(&ssrc.to_be_bytes());
+            reply[8] = 1;
+            let ticks = (start.elapsed().as_micros() / 100) as u64;
+            reply[20..28].copy_from_slice(&ticks.to_be_bytes());
+            Some(reply)
+        }
+        _ => None,
+    }
+}
+
+// the midi commands of an rtp-midi packet back to back, without their delta times,
+// the recovery journal after them is ignored
+fn midi_commands(packet: &[u8], running_status: &mut Option<u8>) -> Option<Vec<u8>> {
+    let version = packet.first()? >> 6;
+    if version != 2 || packet.get(1)? & 0x7F != RTP_MIDI_PAYLOAD_TYPE {
+        return None;
+    }
+    let csrc_count = (packet[0] & 0x0F) as usize;
+    let header_len = RTP_HEADER_LEN + csrc_count * 4;
+    let flags = *packet.get(header_len)?;
+    // B flag, the length takes 12 bits instead of 4
+    let (len, start) = if flags & 0x80 != 0 {
+        (
+            ((flags & 0x0F) as usize) << 8 | *packet.get(header_len + 1)? as usize,
+            header_len + 2,
+        )
+    } else {
+        ((flags & 0x0F) as usize, header_len + 1)
+    };
+    // Z flag, the first command has a delta time too
+    let has_first_delta = flags & 0x20 != 0;
+    let list = packet.get(start..start + len)?;
+
+    let mut bytes = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < list.len() {
+        if i > 0 || has_first_delta {
+            // variable length, up to 4 bytes
+            for _ in 0..4 {
+                let byte = *list.get(i)?;
+                i += 1;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+        let status = *list.get(i)?;
+        let command_len = match status {
+            // sysex runs until its end, or the end of its segment when it's split over packets
+            0xF0 => {
+                let end = list[i + 1..]
+                    .iter()
+                    .position(|byte| matches!(byte, 0xF7 | 0xF0 | 0xF4))?;
+                *running_status = None;
+                end + 2
+            }
+            0xF1 | 0xF3 => {
+                *running_status = None;
+                2
+            }
+            0xF2 => {
+                *running_status = None;
+                3
+            }
+            0xF4..=0xFF => 1,
+            0x80..=0xEF => {
+                *running_status = Some(status);
+                1 + data_len(status)
+            }
+            _ => data_len((*running_status)?),
+        };
+        bytes.extend_from_slice(list.get(i..i + command_len)?);
+        i += command_len;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtp_header() -> Vec<u8> {
+        vec![0x80, 0x61, 0, 1, 0, 0, 0, 0, 1, 2, 3, 4]
+    }
+
+    #[test]
+    fn invitation_is_accepted_with_its_token() {
+        let mut invitation = b"\xFF\xFFIN\0\0\0\x02".to_vec();
+        invitation.extend_from_slice(&[9, 8, 7, 6, 1, 1, 1, 1]);
+        invitation.extend_from_slice(b"phone\0");
+        let reply = session_reply(&invitation, 42, Instant::now()).unwrap();
+        assert_eq!(&reply[..4], b"\xFF\xFFOK");
+        assert_eq!(&reply[8..12], &[9, 8, 7, 6]);
+        assert_eq!(&reply[12..16], &42u32.to_be_bytes());
+        assert_eq!(&reply[16..], b"midi_game\0");
+        // rtp packets aren't session commands
+        assert_eq!(session_reply(&rtp_header(), 42, Instant::now()), None);
+    }
+
+    #[test]
+    fn delta_times_are_taken_out_and_running_status_kept() {
+        let mut packet = rtp_header();
+        // Z flag, 8 bytes: delta, note on, delta of 2 bytes, running status note on
+        packet.extend_from_slice(&[0x28, 0x00, 0x90, 64, 100, 0x81, 0x00, 64, 0]);
+        // a journal that is ignored
+        packet.extend_from_slice(&[0xAA, 0xBB]);
+        let mut running_status = None;
+        let bytes = midi_commands(&packet, &mut running_status).unwrap();
+        assert_eq!(bytes, vec![0x90, 64, 100, 64, 0]);
+        assert_eq!(running_status, Some(0x90));
+
+        // phantom status, the next packet carries on with the status of this one
+        let mut packet = rtp_header();
+        packet.extend_from_slice(&[0x02, 65, 100]);
+        assert_eq!(
+            midi_commands(&packet, &mut running_status),
+            Some(vec![65, 100])
+        );
+        assert_eq!(midi_commands(&packet, &mut None), None);
+    }
+}
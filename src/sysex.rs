@@ -0,0 +1,117 @@
+use std::fmt;
+
+pub const SYSEX_START: u8 = 0xF0;
+pub const SYSEX_END: u8 = 0xF7;
+// longer messages are dropped, device inquiry replies are around 15 bytes
+pub const MAX_SYSEX_LEN: usize = 1024;
+// universal non-real-time identity request, sent to every device id
+pub const DEVICE_INQUIRY: [u8; 6] = [SYSEX_START, 0x7E, 0x7F, 0x06, 0x01, SYSEX_END];
+
+const UNIVERSAL_NON_REAL_TIME: u8 = 0x7E;
+const GENERAL_INFORMATION: u8 = 0x06;
+const IDENTITY_REPLY: u8 = 0x02;
+
+// who the device says it is when answering a device inquiry
+#[derive(Eq, Clone, Debug, PartialEq)]
+pub struct DeviceIdentity {
+    // 1 byte, or 3 starting with 0 for the extended ids
+    pub manufacturer: Vec<u8>,
+    pub family: u16,
+    pub model: u16,
+    pub version: [u8; 4],
+}
+
+impl DeviceIdentity {
+    // None for any sysex that isn't an identity reply
+    pub fn from_reply(bytes: &[u8]) -> Option<Self> {
+        let body = match bytes {
+            [SYSEX_START, UNIVERSAL_NON_REAL_TIME, _device_id, GENERAL_INFORMATION, IDENTITY_REPLY, body @ .., SYSEX_END] => {
+                body
+            }
+            _ => return None,
+        };
+        let manufacturer_len = match body.first()? {
+            0 => 3,
+            _ => 1,
+        };
+        let manufacturer = body.get(..manufacturer_len)?.to_vec();
+        let rest = body.get(manufacturer_len..manufacturer_len + 8)?;
+        Some(Self {
+            manufacturer,
+            family: rest[0] as u16 | (rest[1] as u16) << 7,
+            model: rest[2] as u16 | (rest[3] as u16) << 7,
+            version: [rest[4], rest[5], rest[6], rest[7]],
+        })
+    }
+}
+
+impl fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "manufacturer")?;
+        for byte in self.manufacturer.iter() {
+            write!(f, " {:02x}", byte)?;
+        }
+        write!(
+            f,
+            " family {} model {} version {}.{}.{}.{}",
+            self.family,
+            self.model,
+            self.version[0],
+            self.version[1],
+            self.version[2],
+            self.version[3]
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_message::*;
+
+    #[test]
+    fn identity_reply_with_extended_manufacturer() {
+        let reply = [
+            0xF0, 0x7E, 0x00, 0x06, 0x02, 0x00, 0x20, 0x6B, 0x02, 0x00, 0x04, 0x02, 0x01, 0x00,
+            0x00, 0x01, 0xF7,
+        ];
+        let identity = DeviceIdentity::from_reply(&reply).unwrap();
+        assert_eq!(identity.manufacturer, vec![0x00, 0x20, 0x6B]);
+        assert_eq!((identity.family, identity.model), (2, 0x104));
+        assert_eq!(identity.version, [1, 0, 0, 1]);
+        // cut short
+        assert_eq!(DeviceIdentity::from_reply(&reply[..12]), None);
+        assert_eq!(DeviceIdentity::from_reply(&DEVICE_INQUIRY), None);
+    }
+
+    #[test]
+    fn sysex_split_over_callbacks_keeps_clock_and_running_status() {
+        let mut parser = MidiParser::new();
+        let mut messages = parser.parse(&[0x90, 64, 100, 0xF0, 0x7E, 0x00]);
+        assert!(parser.take_sysex().is_empty());
+        messages.extend(parser.parse(&[0x06, 0xF8, 0x02, 0x42, 0xF7, 0x90, 64, 0]));
+        assert_eq!(
+            parser.take_sysex(),
+            vec![vec![0xF0, 0x7E, 0x00, 0x06, 0x02, 0x42, 0xF7]]
+        );
+        assert_eq!(
+            messages,
+            vec![
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 64,
+                    velocity: 100
+                },
+                MidiMessage::TimingClock,
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 64,
+                    velocity: 0
+                },
+            ]
+        );
+        // a status byte in the middle cuts the sysex off
+        parser.parse(&[0xF0, 0x7E, 0x80, 64, 0, 0xF7]);
+        assert!(parser.take_sysex().is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::constants::*;
 use macroquad::prelude::*;
 use macroquad_tantan_toolbox::water::*;
 
@@ -6,7 +7,7 @@ pub struct MyWater {
 }
 impl MyWater {
     pub fn new(
-        texture_water_raw: Image,
+        texture_water_raw: &Image,
         render_target_texture: Texture2D,
         water_size: Vec2,
         pos: Vec2,
@@ -31,8 +32,8 @@ impl MyWater {
 
         let water_pos = pos;
         let water_dir = vec2(1.0f32, 0.0f32);
-        let water_speed = 0.025f32;
-        let water_strength = 0.02f32;
+        let water_speed = WATER_SPEED;
+        let water_strength = WATER_STRENGTH;
         let mut water = Water::new(
             water_pos,
             water_size,